@@ -1,8 +1,72 @@
+mod approximate;
+pub mod diagnostics;
+mod positional;
+pub mod transform;
+pub mod whitelist;
+
+pub use positional::PositionalExtractor;
+
+/// Finds the capture groups in a read
+pub trait Extractor: Send + Sync {
+    /// Extract the capture groups from a sequence, or return `None` if it does not match
+    fn extract<'a>(&self, sequence: &'a str) -> Option<Extraction<'a>>;
+
+    fn capture_group_names(&self) -> &[String];
+}
+
+/// Extracts capture groups using a regex
 pub struct BarcodeExtractor {
     matcher: regex::Regex,
-    capture_group_names: Vec<String>
+    capture_group_names: Vec<String>,
+    approximate_matcher: Option<approximate::ApproximateMatcher>
+}
+
+/// The capture groups extracted from a single read
+pub struct Extraction<'a> {
+    sequence: &'a str,
+    /// The location of each capture group in the read
+    pub spans: Vec<std::ops::Range<usize>>,
+    /// The number of edits in the constant anchors of the pattern needed to match the read. Always
+    /// 0 unless approximate matching is enabled.
+    pub edits: usize
+}
+
+/// The strand of a read on which an extractor matched
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strand {
+    Forward,
+    /// The extractor matched the reverse complement of the read
+    Reverse,
+}
+
+impl Strand {
+    /// The quality scores of the bases in `span` of the read as oriented on this strand, given the
+    /// quality scores of the read as sequenced
+    pub fn oriented_quality_scores<'a>(&self, quality_scores: &'a [u8], span: &std::ops::Range<usize>) -> std::borrow::Cow<'a, [u8]> {
+        match self {
+            Self::Forward => std::borrow::Cow::Borrowed(&quality_scores[span.clone()]),
+            Self::Reverse => {
+                let length = quality_scores.len();
+                std::borrow::Cow::Owned(quality_scores[length - span.end..length - span.start].iter().rev().copied().collect())
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Strand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Forward => write!(f, "+"),
+            Self::Reverse => write!(f, "-"),
+        }
+    }
 }
 
+impl <'a> Extraction<'a> {
+    pub fn captures(&self) -> impl Iterator<Item = &'a str> + '_ {
+        self.spans.iter().map(|span| &self.sequence[span.clone()])
+    }
+}
 
 impl BarcodeExtractor {
     pub fn new(raw_matcher: &str) -> Result<Self, BarcodeError> {
@@ -14,21 +78,43 @@ impl BarcodeExtractor {
             Ok(Self {
                 matcher,
                 capture_group_names,
+                approximate_matcher: None,
             })
         }
     }
 
-    pub fn extract<'a>(&self, sequence: &'a str) -> Option<Vec<&'a str>> {
+    /// Fall back to approximate matching when the regex does not match exactly. Each constant
+    /// anchor (a literal sequence outside of any capture group) may then contain up to `max_edits`
+    /// mismatches, or insertions and deletions if `allow_indels` is set. The rest of the pattern
+    /// must be fixed-length and still matches exactly, so capture groups are extracted by their
+    /// position relative to the anchors.
+    pub fn approximate(mut self, max_edits: usize, allow_indels: bool) -> Result<Self, BarcodeError> {
+        self.approximate_matcher = Some(approximate::ApproximateMatcher::new(self.matcher.as_str(), max_edits, allow_indels)?);
+        Ok(self)
+    }
+}
+
+impl Extractor for BarcodeExtractor {
+    fn extract<'a>(&self, sequence: &'a str) -> Option<Extraction<'a>> {
         if let Some(captures) = self.matcher.captures(sequence) {
-            Some(self.capture_group_names.iter().filter_map(|capture_group_name| {
-                captures.name(capture_group_name).map(|matched_group| matched_group.as_str())
-            }).collect())
-        } else {
-            None
+            return Some(Extraction {
+                sequence,
+                spans: self.capture_group_names.iter().filter_map(|capture_group_name| {
+                    captures.name(capture_group_name).map(|matched_group| matched_group.range())
+                }).collect(),
+                edits: 0
+            });
         }
+
+        let (spans, edits) = self.approximate_matcher.as_ref()?.find(sequence.as_bytes())?;
+        Some(Extraction {
+            sequence,
+            spans,
+            edits
+        })
     }
 
-    pub fn capture_group_names(&self) -> &[String] {
+    fn capture_group_names(&self) -> &[String] {
         &self.capture_group_names
     }
 }
@@ -42,5 +128,31 @@ pub enum BarcodeError {
         #[from]
         source: regex::Error
     },
+    #[error("barcode-matching regex (\"{0}\") cannot be used for approximate matching: {1}")]
+    UnsupportedApproximatePattern(String, String),
+    #[error("invalid capture group positions (\"{0}\"): {1}")]
+    InvalidPositions(String, String),
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reverse_strand_captures_and_qualities_follow_the_pattern() {
+        let extractor = BarcodeExtractor::new("ACGT(?P<BC>[ACGT]{4})GGTT").expect("valid regex");
+        // The reverse complement of "CCACGTAACGGGTTC"; each base has a different quality score
+        let sequence = "GAACCCGTTACGTGG";
+        let quality_scores = b"ABCDEFGHIJKLMNO";
+        assert!(extractor.extract(sequence).is_none());
+
+        let reverse_sequence = crate::utils::reverse_complement(sequence).expect("valid bases");
+        let extraction = extractor.extract(&reverse_sequence).expect("pattern is on the reverse strand");
+        assert_eq!(extraction.captures().collect::<Vec<_>>(), ["AACG"]);
+        assert_eq!(extraction.spans.len(), 1);
+        assert_eq!(extraction.spans[0], 6..10);
+        // "AACG" was sequenced as "CGTT" at positions 5 to 8, so its first base has the last score
+        assert_eq!(Strand::Reverse.oriented_quality_scores(quality_scores, &extraction.spans[0]).as_ref(), b"IHGF");
+        assert_eq!(Strand::Forward.oriented_quality_scores(quality_scores, &extraction.spans[0]).as_ref(), b"GHIJ");
+    }
+}
@@ -0,0 +1,324 @@
+use regex_syntax::hir::{Class, Hir, HirKind};
+
+use super::BarcodeError;
+
+const UNREACHABLE: u32 = u32::MAX / 2;
+
+/// A single base of a flattened barcode pattern
+struct Position {
+    /// The bases accepted at this position
+    accepted: [bool; 128],
+    /// The constant anchor this position belongs to. Positions outside of anchors (inside capture
+    /// groups, or matching character classes) must always match exactly.
+    anchor: Option<usize>,
+}
+
+/// Matches a fixed-length pattern against a read while tolerating a limited number of edits in
+/// its constant anchors (literal sequences outside of capture groups). Capture groups are located
+/// by their position relative to the anchors, so they never contain indels.
+pub struct ApproximateMatcher {
+    template: Vec<Position>,
+    /// Named capture groups, as (capture index, template start, template end), in capture index order
+    groups: Vec<(u32, usize, usize)>,
+    num_anchors: usize,
+    max_edits: usize,
+    allow_indels: bool,
+}
+
+impl ApproximateMatcher {
+    pub fn new(raw_matcher: &str, max_edits: usize, allow_indels: bool) -> Result<Self, BarcodeError> {
+        let hir = regex_syntax::parse(raw_matcher).map_err(|error| {
+            BarcodeError::UnsupportedApproximatePattern(raw_matcher.to_string(), error.to_string())
+        })?;
+
+        let mut matcher = Self {
+            template: Vec::new(),
+            groups: Vec::new(),
+            num_anchors: 0,
+            max_edits,
+            allow_indels,
+        };
+        matcher.flatten(&hir, false).map_err(|reason| {
+            BarcodeError::UnsupportedApproximatePattern(raw_matcher.to_string(), reason)
+        })?;
+        matcher.groups.sort_by_key(|&(index, _, _)| index);
+        Ok(matcher)
+    }
+
+    fn flatten(&mut self, hir: &Hir, in_group: bool) -> Result<(), String> {
+        match hir.kind() {
+            HirKind::Empty => {},
+            HirKind::Literal(literal) => {
+                for byte in literal.0.iter() {
+                    let mut accepted = [false; 128];
+                    accepted[usize::from(*byte & 0x7f)] = true;
+                    self.push(accepted, !in_group);
+                }
+            },
+            HirKind::Class(class) => {
+                let mut accepted = [false; 128];
+                match class {
+                    Class::Unicode(class) => {
+                        for range in class.ranges() {
+                            for base in u32::from(range.start())..=u32::from(range.end()).min(127) {
+                                accepted[base as usize] = true;
+                            }
+                        }
+                    },
+                    Class::Bytes(class) => {
+                        for range in class.ranges() {
+                            for base in range.start()..=range.end().min(127) {
+                                accepted[usize::from(base)] = true;
+                            }
+                        }
+                    },
+                }
+                self.push(accepted, false);
+            },
+            HirKind::Repetition(repetition) => {
+                if repetition.max != Some(repetition.min) {
+                    return Err("repetitions must have a fixed count".to_string());
+                }
+                for _ in 0..repetition.min {
+                    self.flatten(&repetition.sub, in_group)?;
+                }
+            },
+            HirKind::Capture(capture) => {
+                let start = self.template.len();
+                self.flatten(&capture.sub, true)?;
+                if capture.name.is_some() {
+                    // A capture inside a repetition reports its final iteration, as the regex does
+                    self.groups.retain(|&(index, _, _)| index != capture.index);
+                    self.groups.push((capture.index, start, self.template.len()));
+                }
+            },
+            HirKind::Concat(subs) => {
+                for sub in subs {
+                    self.flatten(sub, in_group)?;
+                }
+            },
+            HirKind::Look(_) => {
+                return Err("look-around assertions are not supported".to_string());
+            },
+            HirKind::Alternation(_) => {
+                return Err("alternations are not supported".to_string());
+            },
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, accepted: [bool; 128], is_anchor: bool) {
+        let anchor = if !is_anchor {
+            None
+        } else if let Some(Position { anchor: Some(anchor), .. }) = self.template.last() {
+            Some(*anchor)
+        } else {
+            self.num_anchors += 1;
+            Some(self.num_anchors - 1)
+        };
+        self.template.push(Position { accepted, anchor });
+    }
+
+    /// The cost of aligning template position `i` to `base`
+    fn substitution_cost(&self, i: usize, base: u8) -> u32 {
+        let position = &self.template[i];
+        if position.accepted[usize::from(base & 0x7f)] {
+            0
+        } else if position.anchor.is_some() {
+            1
+        } else {
+            UNREACHABLE
+        }
+    }
+
+    /// Whether a read base may be inserted between template positions `i - 1` and `i`
+    fn insertion_allowed(&self, i: usize) -> bool {
+        self.allow_indels && i > 0 && i < self.template.len() && self.template[i].anchor.is_some() && self.template[i - 1].anchor == self.template[i].anchor
+    }
+
+    /// Whether template position `i` may be skipped
+    fn deletion_allowed(&self, i: usize) -> bool {
+        self.allow_indels && self.template[i].anchor.is_some()
+    }
+
+    /// Whether template position `i` continues the anchor of the position before it, so that edits
+    /// at both count towards the same anchor
+    fn continues_anchor(&self, i: usize) -> bool {
+        i > 0 && self.template[i].anchor.is_some() && self.template[i - 1].anchor == self.template[i].anchor
+    }
+
+    /// Find the best approximate occurrence of the pattern in `sequence`. Returns the span of each
+    /// named capture group (in capture index order) and the total number of edits in the anchors,
+    /// or `None` if there is no occurrence with at most the allowed number of edits in each anchor.
+    pub fn find(&self, sequence: &[u8]) -> Option<(Vec<std::ops::Range<usize>>, usize)> {
+        let m = self.template.len();
+        let n = sequence.len();
+        let width = n + 1;
+        // Besides the template and read positions, the alignment tracks the number of edits so far in
+        // the anchor of the last template position, so that no anchor exceeds its limit
+        let depth = self.max_edits + 1;
+        let index = |i: usize, j: usize, edits: usize| (i * width + j) * depth + edits;
+
+        // Semi-global alignment: the whole template must align, but it may start and end anywhere
+        // in the read
+        let mut costs = vec![UNREACHABLE; (m + 1) * width * depth];
+        for j in 0..=n {
+            costs[index(0, j, 0)] = 0;
+        }
+        for i in 1..=m {
+            let continues_anchor = self.continues_anchor(i - 1);
+            for j in 0..=n {
+                // A substitution consumes a read base, while a deletion skips the template position
+                let substitution = (j > 0).then(|| (j - 1, self.substitution_cost(i - 1, sequence[j - 1])));
+                let deletion = self.deletion_allowed(i - 1).then_some((j, 1));
+                for (previous_j, step_cost) in [substitution, deletion].into_iter().flatten() {
+                    if step_cost >= UNREACHABLE {
+                        continue;
+                    }
+                    if continues_anchor {
+                        for edits in 0..depth - step_cost as usize {
+                            let cost = costs[index(i - 1, previous_j, edits)] + step_cost;
+                            let entry = &mut costs[index(i, j, edits + step_cost as usize)];
+                            *entry = (*entry).min(cost);
+                        }
+                    } else if (step_cost as usize) < depth {
+                        // A new anchor, or a position outside of anchors, starts counting edits afresh
+                        let cost = (0..depth).map(|edits| costs[index(i - 1, previous_j, edits)]).min().unwrap_or(UNREACHABLE) + step_cost;
+                        let entry = &mut costs[index(i, j, step_cost as usize)];
+                        *entry = (*entry).min(cost);
+                    }
+                }
+                if j > 0 && self.insertion_allowed(i) {
+                    for edits in 1..depth {
+                        let cost = costs[index(i, j - 1, edits - 1)] + 1;
+                        let entry = &mut costs[index(i, j, edits)];
+                        *entry = (*entry).min(cost);
+                    }
+                }
+                for edits in 0..depth {
+                    costs[index(i, j, edits)] = costs[index(i, j, edits)].min(UNREACHABLE);
+                }
+            }
+        }
+
+        let (mut j, mut edits, best_cost) = (0..=n).flat_map(|j| (0..depth).map(move |edits| (j, edits))).map(|(j, edits)| (j, edits, costs[index(m, j, edits)])).min_by_key(|&(_, _, cost)| cost)?;
+        if best_cost >= UNREACHABLE {
+            return None;
+        }
+
+        // The edits in the previous row that lead to `edits` in this row after a step of `step_cost`
+        // into template position `i - 1`, if the previous row reaches `target` that way
+        let previous_edits = |i: usize, previous_j: usize, edits: usize, step_cost: u32, target: u32| -> Option<usize> {
+            if self.continues_anchor(i - 1) {
+                let previous = edits.checked_sub(step_cost as usize)?;
+                (costs[index(i - 1, previous_j, previous)] + step_cost == target).then_some(previous)
+            } else if edits == step_cost as usize {
+                (0..depth).find(|&previous| costs[index(i - 1, previous_j, previous)] + step_cost == target)
+            } else {
+                None
+            }
+        };
+
+        let mut read_positions = vec![0; m];
+        let mut anchor_edits = vec![0; self.num_anchors];
+        let mut i = m;
+        while i > 0 {
+            let cost = costs[index(i, j, edits)];
+            let substitution_cost = if j > 0 { self.substitution_cost(i - 1, sequence[j - 1]) } else { UNREACHABLE };
+            if let Some(previous) = (substitution_cost < UNREACHABLE).then(|| previous_edits(i, j - 1, edits, substitution_cost, cost)).flatten() {
+                if let (Some(anchor), 1) = (self.template[i - 1].anchor, substitution_cost) {
+                    anchor_edits[anchor] += 1;
+                }
+                read_positions[i - 1] = j - 1;
+                i -= 1;
+                j -= 1;
+                edits = previous;
+            } else if let Some(previous) = self.deletion_allowed(i - 1).then(|| previous_edits(i, j, edits, 1, cost)).flatten() {
+                anchor_edits[self.template[i - 1].anchor?] += 1;
+                read_positions[i - 1] = j;
+                i -= 1;
+                edits = previous;
+            } else if j > 0 && edits > 0 && self.insertion_allowed(i) && cost == costs[index(i, j - 1, edits - 1)] + 1 {
+                anchor_edits[self.template[i].anchor?] += 1;
+                j -= 1;
+                edits -= 1;
+            } else {
+                unreachable!("approximate match traceback left the optimal path");
+            }
+        }
+
+        let spans = self.groups.iter().map(|&(_, start, end)| {
+            if start == end {
+                0..0
+            } else {
+                read_positions[start]..read_positions[end - 1] + 1
+            }
+        }).collect();
+        Some((spans, anchor_edits.iter().sum()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATTERN: &str = "ACGTAC(?P<BC>[ACGT]{4})GGTTCC";
+
+    /// The span of the pattern's single capture group in `read`, and the number of edits
+    fn find(read: &str, max_edits: usize, allow_indels: bool) -> Option<(std::ops::Range<usize>, usize)> {
+        let (spans, edits) = ApproximateMatcher::new(PATTERN, max_edits, allow_indels).expect("pattern is supported").find(read.as_bytes())?;
+        assert_eq!(spans.len(), 1);
+        Some((spans[0].clone(), edits))
+    }
+
+    #[test]
+    fn exact_matches_have_no_edits() {
+        assert_eq!(find("TTACGTACAAAAGGTTCCTT", 1, false), Some((8..12, 0)));
+    }
+
+    #[test]
+    fn mismatches_count_towards_each_anchor() {
+        // One mismatch in each anchor
+        assert_eq!(find("TTACGAACAAAAGGTTGCTT", 1, false), Some((8..12, 2)));
+        // Two mismatches in the first anchor exceed its limit, even though the total is within
+        // what both anchors together allow
+        assert_eq!(find("TTACTAACAAAAGGTTCCTT", 1, false), None);
+        assert_eq!(find("TTACTAACAAAAGGTTCCTT", 2, false), Some((8..12, 2)));
+    }
+
+    #[test]
+    fn anchor_limits_apply_during_the_search() {
+        // Both occurrences have two edits, and the first one is taken when it is within the limits
+        let read = "ACTTTCAAAAGGTTCCTTACGAACCCCCGGTTGC";
+        assert_eq!(find(read, 2, false), Some((6..10, 2)));
+        // Both of its edits are in its first anchor, though, so with one edit per anchor only the
+        // second occurrence, with one edit in each anchor, is valid
+        assert_eq!(find(read, 1, false), Some((24..28, 2)));
+        assert_eq!(find(read, 1, true), Some((24..28, 2)));
+    }
+
+    #[test]
+    fn capture_groups_must_match_exactly() {
+        assert_eq!(find("TTACGTACAANAGGTTCCTT", 2, false), None);
+    }
+
+    #[test]
+    fn indels_need_allow_indels() {
+        // The first anchor is missing its "T", so the capture group starts one base earlier
+        let read = "TTACGACAAAAGGTTCCTT";
+        assert_eq!(find(read, 1, false), None);
+        assert_eq!(find(read, 1, true), Some((7..11, 1)));
+        // An extra base inside the second anchor
+        assert_eq!(find("TTACGTACAAAAGGTATCCTT", 1, true), Some((8..12, 1)));
+    }
+
+    #[test]
+    fn ties_take_the_first_occurrence() {
+        assert_eq!(find("ACGTACAAAAGGTTCCACGTACCCCCGGTTCC", 0, false), Some((6..10, 0)));
+    }
+
+    #[test]
+    fn variable_length_patterns_are_rejected() {
+        assert!(matches!(ApproximateMatcher::new("ACGT(?P<BC>[ACGT]{4,6})GG", 1, false), Err(BarcodeError::UnsupportedApproximatePattern(..))));
+    }
+}
@@ -0,0 +1,294 @@
+use std::{collections::HashMap, io::{self, prelude::*}};
+
+use itertools::Itertools;
+use regex_syntax::hir::{Hir, HirKind};
+
+use super::BarcodeError;
+use crate::utils;
+
+/// The number of leading bases by which failing reads are grouped into failure sequences
+const FAILURE_SEQUENCE_LENGTH: usize = 30;
+
+/// The number of most common sequences reported for each anchor and source
+const MAX_REPORTED_SEQUENCES: usize = 10;
+
+/// One top-level element of a barcode-matching regex
+enum Segment {
+    /// A constant sequence outside of any capture group
+    Anchor(String),
+    Capture(String),
+    /// Anything else, such as a character class or an unnamed group, as a regex
+    Pattern(String),
+}
+
+impl Segment {
+    fn from_hir(hir: &Hir) -> Self {
+        match hir.kind() {
+            HirKind::Literal(literal) => Self::Anchor(String::from_utf8_lossy(&literal.0).into_owned()),
+            HirKind::Capture(regex_syntax::hir::Capture { name: Some(name), .. }) => Self::Capture(name.to_string()),
+            _ => Self::Pattern(hir.to_string()),
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Anchor(_) => "anchor",
+            Self::Capture(_) => "capture",
+            Self::Pattern(_) => "pattern",
+        }
+    }
+
+    fn label(&self) -> &str {
+        match self {
+            Self::Anchor(label) | Self::Capture(label) | Self::Pattern(label) => label,
+        }
+    }
+}
+
+/// How far one read got through a pattern before it failed to match
+struct Diagnosis {
+    /// The index of the first segment that could not be matched after the ones before it
+    failed_segment: usize,
+    /// For each anchor segment, its index, the fewest edits with which it aligns to the read, and
+    /// the part of the read it aligns to
+    anchor_alignments: Vec<(usize, usize, String)>,
+}
+
+/// Why one regex failed to match the reads of its source
+struct PatternDiagnostics {
+    segments: Vec<Segment>,
+    /// For each segment, a regex matching it and every segment before it
+    prefixes: Vec<regex::Regex>,
+    reads_analysed: usize,
+    /// The number of reads that first failed at each segment
+    failed_segments: Vec<usize>,
+    /// The number of reads in which each anchor (by segment index) aligned with each number of edits
+    anchor_edits: HashMap<(usize, usize), usize>,
+    /// The number of reads in which each anchor (by segment index) aligned to each sequence
+    anchor_sequences: HashMap<(usize, String), usize>,
+    /// The number of reads with each leading sequence that failed at each segment
+    failure_sequences: HashMap<(String, usize), usize>,
+}
+
+impl PatternDiagnostics {
+    fn new(raw_matcher: &str) -> Result<Self, BarcodeError> {
+        let hir = regex_syntax::parse(raw_matcher).map_err(|error| regex::Error::Syntax(error.to_string()))?;
+        let parts: Vec<Hir> = match hir.kind() {
+            HirKind::Concat(subs) => subs.clone(),
+            _ => vec![hir],
+        };
+        let prefixes = (1..=parts.len()).map(|length| regex::Regex::new(&Hir::concat(parts[..length].to_vec()).to_string())).collect::<Result<_, _>>()?;
+        Ok(Self {
+            segments: parts.iter().map(Segment::from_hir).collect(),
+            prefixes,
+            reads_analysed: 0,
+            failed_segments: vec![0; parts.len()],
+            anchor_edits: HashMap::new(),
+            anchor_sequences: HashMap::new(),
+            failure_sequences: HashMap::new(),
+        })
+    }
+
+    fn diagnose(&self, sequence: &str) -> Diagnosis {
+        let failed_segment = self.prefixes.iter().position(|prefix| !prefix.is_match(sequence)).unwrap_or(self.segments.len());
+        let anchor_alignments = self.segments.iter().enumerate().filter_map(|(index, segment)| match segment {
+            Segment::Anchor(anchor) => {
+                let (edits, span) = best_alignment(anchor.as_bytes(), sequence.as_bytes());
+                Some((index, edits, String::from_utf8_lossy(&sequence.as_bytes()[span]).into_owned()))
+            },
+            _ => None,
+        }).collect();
+        Diagnosis { failed_segment, anchor_alignments }
+    }
+
+    fn record(&mut self, sequence: &str, diagnosis: Diagnosis) {
+        self.reads_analysed += 1;
+        if let Some(failed_reads) = self.failed_segments.get_mut(diagnosis.failed_segment) {
+            *failed_reads += 1;
+        }
+        for (index, edits, aligned_sequence) in diagnosis.anchor_alignments {
+            *self.anchor_edits.entry((index, edits)).or_insert(0) += 1;
+            *self.anchor_sequences.entry((index, aligned_sequence)).or_insert(0) += 1;
+        }
+        let leading_sequence = sequence.get(..FAILURE_SEQUENCE_LENGTH).unwrap_or(sequence).to_string();
+        *self.failure_sequences.entry((leading_sequence, diagnosis.failed_segment)).or_insert(0) += 1;
+    }
+
+    fn segment_label(&self, index: usize) -> &str {
+        self.segments.get(index).map_or("", Segment::label)
+    }
+
+    fn write<W: Write>(&self, out: &mut W, source: usize) -> io::Result<()> {
+        let fraction = |reads: usize| if self.reads_analysed > 0 { reads as f64 / self.reads_analysed as f64 } else { 0.0 };
+
+        for (segment, reads) in self.segments.iter().zip(self.failed_segments.iter()) {
+            writeln!(out, "{}\tfailed_segment\t{}\t{}\t{}\t{}", source, segment.label(), segment.kind(), reads, fraction(*reads))?;
+        }
+
+        for ((index, edits), reads) in self.anchor_edits.iter().sorted() {
+            writeln!(out, "{}\tanchor_edits\t{}\t{}\t{}\t{}", source, self.segment_label(*index), edits, reads, fraction(*reads))?;
+        }
+
+        for (index, sequences) in &self.anchor_sequences.iter().sorted_by_key(|((index, _), _)| *index).group_by(|((index, _), _)| *index) {
+            for ((_, aligned_sequence), reads) in sequences.sorted_by(|(first_key, first_reads), (second_key, second_reads)| second_reads.cmp(first_reads).then(first_key.cmp(second_key))).take(MAX_REPORTED_SEQUENCES) {
+                writeln!(out, "{}\tanchor_alignment\t{}\t{}\t{}\t{}", source, self.segment_label(index), aligned_sequence, reads, fraction(*reads))?;
+            }
+        }
+
+        // Each leading sequence is reported with the segment its reads most often failed at
+        let mut failure_sequences: HashMap<&str, (usize, usize, usize)> = HashMap::new();
+        for ((leading_sequence, failed_segment), reads) in self.failure_sequences.iter() {
+            let (total_reads, most_common_segment, most_common_reads) = failure_sequences.entry(leading_sequence.as_str()).or_insert((0, *failed_segment, 0));
+            *total_reads += reads;
+            if *reads > *most_common_reads || (*reads == *most_common_reads && *failed_segment < *most_common_segment) {
+                *most_common_segment = *failed_segment;
+                *most_common_reads = *reads;
+            }
+        }
+        for (leading_sequence, (reads, failed_segment, _)) in failure_sequences.into_iter().sorted_by(|(first_sequence, (first_reads, _, _)), (second_sequence, (second_reads, _, _))| second_reads.cmp(first_reads).then(first_sequence.cmp(second_sequence))).take(MAX_REPORTED_SEQUENCES) {
+            writeln!(out, "{}\tfailure_sequence\t{}\t{}\t{}\t{}", source, leading_sequence, self.segment_label(failed_segment), reads, fraction(reads))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Explains why reads did not match each source's regex, from a sample of the reads that failed.
+/// Each regex is split into its top-level segments (constant anchors, capture groups and other
+/// patterns), and each failing read is attributed to the first segment that cannot be matched
+/// along with all of the segments before it.
+pub struct FailureDiagnostics {
+    patterns: Vec<PatternDiagnostics>,
+    /// The number of failing reads analysed for each source
+    max_reads: usize,
+    /// Whether reads are also diagnosed as their reverse complement, keeping whichever strand gets
+    /// further through the pattern
+    both_strands: bool,
+}
+
+impl FailureDiagnostics {
+    pub fn new<'a>(raw_matchers: impl IntoIterator<Item = &'a str>, max_reads: usize, both_strands: bool) -> Result<Self, BarcodeError> {
+        Ok(Self {
+            patterns: raw_matchers.into_iter().map(PatternDiagnostics::new).collect::<Result<_, _>>()?,
+            max_reads,
+            both_strands,
+        })
+    }
+
+    /// Record a read from the source at `index` (0-based) that its regex did not match
+    pub fn record_failure(&mut self, index: usize, sequence: &str) {
+        let pattern = &mut self.patterns[index];
+        if pattern.reads_analysed >= self.max_reads {
+            return;
+        }
+        let mut diagnosis = pattern.diagnose(sequence);
+        if self.both_strands {
+            if let Ok(reverse_sequence) = utils::reverse_complement(sequence) {
+                let reverse_diagnosis = pattern.diagnose(&reverse_sequence);
+                if reverse_diagnosis.failed_segment > diagnosis.failed_segment {
+                    diagnosis = reverse_diagnosis;
+                }
+            }
+        }
+        pattern.record(sequence, diagnosis);
+    }
+
+    /// Write the summary as a TSV, with a row for each segment each source's reads failed at, each
+    /// number of edits and most common aligned sequences of each anchor, and the most common
+    /// leading sequences of failing reads
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "source\tsection\titem\tdetail\treads\tfraction")?;
+        for (index, pattern) in self.patterns.iter().enumerate() {
+            pattern.write(&mut out, index + 1)?;
+        }
+        out.flush()
+    }
+}
+
+/// The fewest edits with which `anchor` aligns to any part of `sequence`, and the span of that part
+fn best_alignment(anchor: &[u8], sequence: &[u8]) -> (usize, std::ops::Range<usize>) {
+    // Semi-global alignment, tracking where in the read each alignment starts: the whole anchor
+    // must align, but it may start and end anywhere in the read
+    let mut previous: Vec<(usize, usize)> = (0..=sequence.len()).map(|j| (0, j)).collect();
+    let mut current: Vec<(usize, usize)> = vec![(0, 0); sequence.len() + 1];
+    for (i, anchor_base) in anchor.iter().enumerate() {
+        current[0] = (i + 1, 0);
+        for (j, base) in sequence.iter().enumerate() {
+            let (diagonal_cost, diagonal_start) = previous[j];
+            let mut best = (diagonal_cost + usize::from(anchor_base != base), diagonal_start);
+            if previous[j + 1].0 + 1 < best.0 {
+                best = (previous[j + 1].0 + 1, previous[j + 1].1);
+            }
+            if current[j].0 + 1 < best.0 {
+                best = (current[j].0 + 1, current[j].1);
+            }
+            current[j + 1] = best;
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    let (end, (edits, start)) = previous.iter().copied().enumerate().min_by_key(|(_, (edits, _))| *edits).unwrap_or((0, (anchor.len(), 0)));
+    (edits, start..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anchors_align_anywhere_in_the_read() {
+        assert_eq!(best_alignment(b"ACGT", b"TTACGTTT"), (0, 2..6));
+        assert_eq!(best_alignment(b"ACGT", b"TTACCTTT"), (1, 2..6));
+        assert_eq!(best_alignment(b"ACGT", b"ACG"), (1, 0..3));
+        assert_eq!(best_alignment(b"ACGGT", b"TTACGTTT").0, 1);
+    }
+
+    #[test]
+    fn absent_anchors_need_many_edits() {
+        assert_eq!(best_alignment(b"ACGT", b""), (4, 0..0));
+        assert_eq!(best_alignment(b"ACGT", b"GGGG").0, 3);
+    }
+
+    /// The rows of one section of the diagnostics TSV, without the source and section columns
+    fn section_rows(diagnostics: &FailureDiagnostics, section: &str) -> Vec<String> {
+        let mut out = Vec::new();
+        diagnostics.write(&mut out).expect("failed to write diagnostics");
+        String::from_utf8(out).expect("diagnostics are UTF-8").lines()
+            .filter_map(|line| line.strip_prefix(&format!("1\t{}\t", section)).map(str::to_string))
+            .collect()
+    }
+
+    #[test]
+    fn reads_fail_at_the_first_unmatched_segment() {
+        let mut diagnostics = FailureDiagnostics::new(["ACGT(?P<BC>[ACGT]{4})TTGG"], 10, false).expect("valid regex");
+        for sequence in ["GGGGAAAATTGG", "ACGTAA", "ACGTAAAACCCC", "ACGTCCCCCCCC"] {
+            diagnostics.record_failure(0, sequence);
+        }
+        assert_eq!(section_rows(&diagnostics, "failed_segment"), ["ACGT\tanchor\t1\t0.25", "BC\tcapture\t1\t0.25", "TTGG\tanchor\t2\t0.5"]);
+        assert_eq!(section_rows(&diagnostics, "failure_sequence"), [
+            "ACGTAA\tBC\t1\t0.25",
+            "ACGTAAAACCCC\tTTGG\t1\t0.25",
+            "ACGTCCCCCCCC\tTTGG\t1\t0.25",
+            "GGGGAAAATTGG\tACGT\t1\t0.25",
+        ]);
+    }
+
+    #[test]
+    fn failure_sequences_group_reads_by_their_leading_bases() {
+        let mut diagnostics = FailureDiagnostics::new(["ACGT(?P<BC>[ACGT]{4})"], 3, false).expect("valid regex");
+        let leading = "G".repeat(FAILURE_SEQUENCE_LENGTH);
+        for suffix in ["A", "C", "T", "G"] {
+            diagnostics.record_failure(0, &format!("{}{}", leading, suffix));
+        }
+        // Only the first three reads are analysed
+        assert_eq!(section_rows(&diagnostics, "failure_sequence"), [format!("{}\tACGT\t3\t1", leading)]);
+        assert_eq!(section_rows(&diagnostics, "failed_segment"), ["ACGT\tanchor\t3\t1", "BC\tcapture\t0\t0"]);
+    }
+
+    #[test]
+    fn reverse_complements_are_diagnosed_with_both_strands() {
+        let mut diagnostics = FailureDiagnostics::new(["ACGT(?P<BC>[ACGT]{4})TTGG"], 10, true).expect("valid regex");
+        // The reverse complement of ACGTAAAACC, which gets as far as the last anchor
+        diagnostics.record_failure(0, "GGTTTTACGT");
+        assert_eq!(section_rows(&diagnostics, "failed_segment"), ["ACGT\tanchor\t0\t0", "BC\tcapture\t0\t0", "TTGG\tanchor\t1\t1"]);
+    }
+}
@@ -0,0 +1,139 @@
+use std::ops::Range;
+
+use super::{BarcodeError, Extraction, Extractor};
+
+/// Extracts capture groups at fixed offsets, either from the start of the read or from the first
+/// occurrence of a constant anchor sequence. Much faster than a regex for well-behaved amplicons.
+pub struct PositionalExtractor {
+    anchor: Option<String>,
+    capture_group_names: Vec<String>,
+    /// The (end-exclusive) offsets of each capture group, relative to the start of the anchor or
+    /// read
+    offsets: Vec<(isize, isize)>,
+}
+
+impl PositionalExtractor {
+    /// Parse capture group positions written as `[ANCHOR@]NAME:START-END[,NAME:START-END...]`, with
+    /// 0-based, end-exclusive offsets. With an anchor, offsets are relative to its start and may be
+    /// negative to refer to bases before it, e.g. `AACTCTTACTGCCC@BC1:-20-0,BC2:14-40`.
+    pub fn new(raw_positions: &str) -> Result<Self, BarcodeError> {
+        let invalid = |message: String| BarcodeError::InvalidPositions(raw_positions.to_string(), message);
+
+        let (anchor, raw_groups) = match raw_positions.split_once('@') {
+            Some((anchor, raw_groups)) => {
+                if anchor.is_empty() {
+                    return Err(invalid("anchor is empty".to_string()));
+                }
+                (Some(anchor.to_string()), raw_groups)
+            },
+            None => (None, raw_positions),
+        };
+
+        let mut capture_group_names = Vec::new();
+        let mut offsets = Vec::new();
+        for raw_group in raw_groups.split(',') {
+            let (name, raw_range) = raw_group.split_once(':').ok_or_else(|| invalid(format!("\"{}\" should be NAME:START-END", raw_group)))?;
+            if name.is_empty() || capture_group_names.iter().any(|existing_name| existing_name == name) {
+                return Err(invalid(format!("capture group name \"{}\" is empty or duplicated", name)));
+            }
+            let (start, end) = parse_range(raw_range).ok_or_else(|| invalid(format!("\"{}\" should be START-END", raw_range)))?;
+            if start >= end {
+                return Err(invalid(format!("capture group \"{}\" must start before it ends", name)));
+            }
+            if anchor.is_none() && start < 0 {
+                return Err(invalid(format!("capture group \"{}\" can only have a negative offset relative to an anchor", name)));
+            }
+            capture_group_names.push(name.to_string());
+            offsets.push((start, end));
+        }
+
+        Ok(Self {
+            anchor,
+            capture_group_names,
+            offsets,
+        })
+    }
+}
+
+impl Extractor for PositionalExtractor {
+    fn extract<'a>(&self, sequence: &'a str) -> Option<Extraction<'a>> {
+        let origin = match self.anchor {
+            Some(ref anchor) => sequence.find(anchor.as_str())? as isize,
+            None => 0,
+        };
+        let spans = self.offsets.iter().map(|(start, end)| {
+            let start = usize::try_from(origin + start).ok()?;
+            let end = usize::try_from(origin + end).ok().filter(|end| *end <= sequence.len())?;
+            Some(Range { start, end })
+        }).collect::<Option<Vec<_>>>()?;
+        Some(Extraction {
+            sequence,
+            spans,
+            edits: 0,
+        })
+    }
+
+    fn capture_group_names(&self) -> &[String] {
+        &self.capture_group_names
+    }
+}
+
+/// Parse a range written as `START-END`, where either offset may be negative (e.g. `-20--5`)
+fn parse_range(raw_range: &str) -> Option<(isize, isize)> {
+    // The separating hyphen is the first one that is not a leading minus sign
+    let separator = raw_range.char_indices().skip(1).find(|(_, character)| *character == '-')?.0;
+    let start = raw_range[..separator].parse::<isize>().ok()?;
+    let end = raw_range[separator + 1..].parse::<isize>().ok()?;
+    Some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The captures `positions` extracts from `sequence`
+    fn captures<'a>(positions: &str, sequence: &'a str) -> Option<Vec<&'a str>> {
+        let extractor = PositionalExtractor::new(positions).expect("valid positions");
+        Some(extractor.extract(sequence)?.captures().collect())
+    }
+
+    #[test]
+    fn ranges_may_have_negative_offsets() {
+        assert_eq!(parse_range("0-8"), Some((0, 8)));
+        assert_eq!(parse_range("-20-0"), Some((-20, 0)));
+        assert_eq!(parse_range("-20--5"), Some((-20, -5)));
+        assert_eq!(parse_range("4-16"), Some((4, 16)));
+    }
+
+    #[test]
+    fn malformed_ranges_are_rejected() {
+        for raw_range in ["", "8", "-8", "4-", "-4-", "a-8", "4-b", "1-2-3", "4--"] {
+            assert_eq!(parse_range(raw_range), None, "\"{}\" is accepted", raw_range);
+        }
+        for raw_positions in ["BC:4", "BC:8-4", "BC:4-4", "BC:-4-0", "@BC:0-4", "BC:0-4,BC:4-8", ":0-4"] {
+            assert!(matches!(PositionalExtractor::new(raw_positions), Err(BarcodeError::InvalidPositions(..))), "\"{}\" is accepted", raw_positions);
+        }
+    }
+
+    #[test]
+    fn offsets_are_relative_to_the_read_or_anchor() {
+        assert_eq!(captures("BC1:0-4,BC2:6-8", "ACGTTTGGCC"), Some(vec!["ACGT", "GG"]));
+        assert_eq!(captures("TTGG@BC1:-4-0,BC2:4-6", "ACGTTTGGCC"), Some(vec!["ACGT", "CC"]));
+        // The first occurrence of the anchor is used
+        assert_eq!(captures("TT@BC:2-4", "TTACTTGG"), Some(vec!["AC"]));
+        assert_eq!(captures("GG@BC:2-4", "TTACTTGG"), None);
+    }
+
+    #[test]
+    fn captures_must_lie_within_the_read() {
+        // Anchors near either end of the read leave no room for a capture beyond that end
+        assert_eq!(captures("TTGG@BC:-5-0", "ACGTTTGGCC"), None);
+        assert_eq!(captures("TTGG@BC:-4-0", "ACGTTTGGCC"), Some(vec!["ACGT"]));
+        assert_eq!(captures("TTGG@BC:4-7", "ACGTTTGGCC"), None);
+        assert_eq!(captures("TTGG@BC:4-6", "ACGTTTGGCC"), Some(vec!["CC"]));
+        assert_eq!(captures("BC:0-11", "ACGTTTGGCC"), None);
+        assert_eq!(captures("BC:0-10", "ACGTTTGGCC"), Some(vec!["ACGTTTGGCC"]));
+        // Reads without the anchor do not match
+        assert_eq!(captures("AAAA@BC:0-4", "ACGTTTGGCC"), None);
+    }
+}
@@ -0,0 +1,116 @@
+use std::{ops::Range, str::FromStr};
+
+use crate::utils;
+
+/// An operation applied to a captured sequence before it is written out
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Transform {
+    ReverseComplement,
+    Uppercase,
+    TrimStart(usize),
+    TrimEnd(usize),
+    /// Remove the bases in each (0-based, end-exclusive) range, e.g. fixed spacers inside a barcode.
+    /// All ranges refer to positions in the sequence before any of them are removed.
+    Remove(Vec<Range<usize>>),
+}
+
+impl Transform {
+    pub fn apply(&self, sequence: &str) -> Result<String, TransformError> {
+        match self {
+            Self::ReverseComplement => Ok(utils::reverse_complement(sequence)?),
+            Self::Uppercase => Ok(sequence.to_ascii_uppercase()),
+            Self::TrimStart(length) => Ok(sequence.get(*length..).unwrap_or("").to_string()),
+            Self::TrimEnd(length) => Ok(sequence[..sequence.len().saturating_sub(*length)].to_string()),
+            Self::Remove(ranges) => {
+                if let Some(range) = ranges.iter().find(|range| range.end > sequence.len()) {
+                    return Err(TransformError::RangeOutOfBounds(range.clone(), sequence.to_string()));
+                }
+                Ok(sequence.char_indices().filter(|(index, _)| !ranges.iter().any(|range| range.contains(index))).map(|(_, base)| base).collect())
+            },
+        }
+    }
+}
+
+impl FromStr for Transform {
+    type Err = String;
+
+    /// Parse a transform written as `rc`, `upper`, `trim-start=N`, `trim-end=N` or
+    /// `remove=START-END[+START-END...]`
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let (operation, argument) = match raw.split_once('=') {
+            Some((operation, argument)) => (operation, Some(argument)),
+            None => (raw, None),
+        };
+        let parse_length = |argument: Option<&str>| -> Result<usize, String> {
+            argument.ok_or_else(|| format!("\"{}\" requires a length", operation))?.parse::<usize>().map_err(|error| format!("invalid length for \"{}\": {}", operation, error))
+        };
+        match operation {
+            "rc" | "reverse-complement" => Ok(Self::ReverseComplement),
+            "upper" | "uppercase" => Ok(Self::Uppercase),
+            "trim-start" => Ok(Self::TrimStart(parse_length(argument)?)),
+            "trim-end" => Ok(Self::TrimEnd(parse_length(argument)?)),
+            "remove" => {
+                let ranges = argument.ok_or_else(|| "\"remove\" requires one or more ranges".to_string())?.split('+').map(|raw_range| {
+                    let (start, end) = raw_range.split_once('-').ok_or_else(|| format!("invalid range \"{}\"; expected START-END", raw_range))?;
+                    let start = start.parse::<usize>().map_err(|error| format!("invalid range start \"{}\": {}", start, error))?;
+                    let end = end.parse::<usize>().map_err(|error| format!("invalid range end \"{}\": {}", end, error))?;
+                    if start >= end {
+                        return Err(format!("invalid range \"{}\"; start must be before end", raw_range));
+                    }
+                    Ok(start..end)
+                }).collect::<Result<Vec<_>, String>>()?;
+                Ok(Self::Remove(ranges))
+            },
+            _ => Err(format!("unknown transform \"{}\"", operation)),
+        }
+    }
+}
+
+/// Parse the transforms for a capture group, written as `GROUP:TRANSFORM[,TRANSFORM...]`
+pub fn parse_group_transforms(raw: &str) -> Result<(String, Vec<Transform>), String> {
+    let (group, raw_transforms) = raw.split_once(':').ok_or_else(|| format!("invalid transform \"{}\"; expected GROUP:TRANSFORM[,TRANSFORM...]", raw))?;
+    let transforms = raw_transforms.split(',').map(Transform::from_str).collect::<Result<Vec<_>, _>>()?;
+    Ok((group.to_string(), transforms))
+}
+
+/// Apply a series of transforms to a captured sequence, in order
+pub fn apply_all(transforms: &[Transform], sequence: &str) -> Result<String, TransformError> {
+    let mut transformed = sequence.to_string();
+    for transform in transforms {
+        transformed = transform.apply(&transformed)?;
+    }
+    Ok(transformed)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TransformError {
+    #[error(transparent)]
+    InvalidSequence {
+        #[from]
+        source: utils::SequenceError
+    },
+    #[error("range {0:?} is outside of captured sequence \"{1}\"")]
+    RangeOutOfBounds(Range<usize>, String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reverse_complement_keeps_case() {
+        assert_eq!(apply_all(&[Transform::ReverseComplement], "ACgtN").expect("valid bases"), "NacGT");
+    }
+
+    #[test]
+    fn iupac_bases_cannot_be_reverse_complemented() {
+        assert!(matches!(apply_all(&[Transform::ReverseComplement], "CRCC"), Err(TransformError::InvalidSequence { .. })));
+    }
+
+    #[test]
+    fn transforms_apply_in_order() {
+        let transforms = [Transform::TrimStart(1), Transform::Remove(vec![0..1, 2..4]), Transform::Uppercase];
+        assert_eq!(apply_all(&transforms, "aacgtt").expect("ranges within the sequence"), "CT");
+        assert!(matches!(apply_all(&transforms, "aac"), Err(TransformError::RangeOutOfBounds(..))));
+    }
+}
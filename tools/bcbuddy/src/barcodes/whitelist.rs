@@ -0,0 +1,197 @@
+use std::{collections::HashSet, fmt, fs, io::{self, prelude::*}, path};
+
+/// The bases substituted into a barcode when searching for nearby whitelist entries
+const BASES: [u8; 4] = *b"ACGT";
+
+/// A list of the barcodes expected for a capture group, used to correct sequencing errors in
+/// extracted barcodes
+pub struct Whitelist {
+    barcodes: HashSet<Vec<u8>>,
+    max_distance: usize,
+}
+
+/// The result of comparing an extracted barcode to a whitelist
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Correction {
+    /// The barcode is in the whitelist
+    Exact,
+    /// Exactly one whitelist entry is closest to the barcode, at this Hamming distance
+    Corrected(String, usize),
+    /// Several whitelist entries are equally close to the barcode
+    Ambiguous,
+    /// No whitelist entry is within the maximum distance of the barcode
+    Unmatched,
+}
+
+/// Written in the distance column of the output
+impl fmt::Display for Correction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exact => write!(f, "0"),
+            Self::Corrected(_, distance) => write!(f, "{}", distance),
+            Self::Ambiguous => write!(f, "ambiguous"),
+            Self::Unmatched => write!(f, "unmatched"),
+        }
+    }
+}
+
+impl Whitelist {
+    /// Read a whitelist, either with one barcode per line or as a tab-separated file with a header,
+    /// in which case barcodes are taken from the column named after the capture group or, failing
+    /// that, "BC" (as in the long-read barcode-variant map)
+    pub fn read(path: &path::Path, group_name: &str, max_distance: usize) -> Result<Self, WhitelistError> {
+        let mut lines = io::BufReader::new(fs::File::open(path)?).lines().filter(|line| {
+            !matches!(line, Ok(line) if line.trim().is_empty() || line.starts_with('#'))
+        }).peekable();
+
+        let column = match lines.peek() {
+            Some(Ok(header)) if header.contains('\t') => {
+                let columns: Vec<&str> = header.trim_end().split('\t').collect();
+                let column = columns.iter().position(|column| *column == group_name).or_else(|| columns.iter().position(|column| *column == "BC"));
+                match column {
+                    Some(column) => {
+                        lines.next();
+                        column
+                    },
+                    None => { return Err(WhitelistError::MissingColumn(group_name.to_string())); }
+                }
+            },
+            _ => 0,
+        };
+
+        let mut barcodes = HashSet::new();
+        for line in lines {
+            let line = line?;
+            let barcode = line.trim_end().split('\t').nth(column).unwrap_or("");
+            if barcode.is_empty() {
+                continue;
+            }
+            barcodes.insert(barcode.to_ascii_uppercase().into_bytes());
+        }
+        if barcodes.is_empty() {
+            return Err(WhitelistError::Empty(path.display().to_string()));
+        }
+
+        Ok(Self {
+            barcodes,
+            max_distance,
+        })
+    }
+
+    /// Find the whitelist entry closest to a barcode by Hamming distance. Candidates are generated by
+    /// substituting bases, so the cost of correcting a barcode grows quickly with the maximum
+    /// distance.
+    pub fn correct(&self, barcode: &str) -> Correction {
+        let mut barcode = barcode.to_ascii_uppercase().into_bytes();
+        if self.barcodes.contains(&barcode) {
+            return Correction::Exact;
+        }
+        for distance in 1..=self.max_distance.min(barcode.len()) {
+            let mut matches = Vec::new();
+            self.find_substitutions(&mut barcode, 0, distance, &mut matches);
+            match matches.len() {
+                0 => {},
+                1 => { return Correction::Corrected(String::from_utf8(matches.remove(0)).expect("whitelist barcodes are read from UTF-8 text"), distance); },
+                _ => { return Correction::Ambiguous; }
+            }
+        }
+        Correction::Unmatched
+    }
+
+    /// Add each whitelist entry that differs from `barcode` by exactly `remaining` substitutions at
+    /// or after `start` to `matches`, stopping early once there is more than one
+    fn find_substitutions(&self, barcode: &mut [u8], start: usize, remaining: usize, matches: &mut Vec<Vec<u8>>) {
+        for position in start..barcode.len() {
+            let original = barcode[position];
+            for base in BASES.iter().filter(|base| **base != original) {
+                barcode[position] = *base;
+                if remaining == 1 {
+                    if self.barcodes.contains(&*barcode) {
+                        matches.push(barcode.to_vec());
+                    }
+                } else {
+                    self.find_substitutions(barcode, position + 1, remaining - 1, matches);
+                }
+                if matches.len() > 1 {
+                    barcode[position] = original;
+                    return;
+                }
+            }
+            barcode[position] = original;
+        }
+    }
+}
+
+/// Parse a whitelist for a capture group, written as `GROUP:PATH`
+pub fn parse_group_whitelist(raw: &str) -> Result<(String, path::PathBuf), String> {
+    let (group, path) = raw.split_once(':').ok_or_else(|| format!("invalid whitelist \"{}\"; expected GROUP:PATH", raw))?;
+    Ok((group.to_string(), path.into()))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum WhitelistError {
+    #[error("failed to read whitelist")]
+    Reading {
+        #[from]
+        source: io::Error
+    },
+    #[error("whitelist has a header but no \"{0}\" or \"BC\" column")]
+    MissingColumn(String),
+    #[error("whitelist \"{0}\" contains no barcodes")]
+    Empty(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn whitelist(barcodes: &[&str], max_distance: usize) -> Whitelist {
+        Whitelist {
+            barcodes: barcodes.iter().map(|barcode| barcode.as_bytes().to_vec()).collect(),
+            max_distance,
+        }
+    }
+
+    #[test]
+    fn exact_matches_ignore_case() {
+        assert_eq!(whitelist(&["ACGT"], 1).correct("acgt"), Correction::Exact);
+    }
+
+    #[test]
+    fn unique_nearest_entries_are_corrections() {
+        let whitelist = whitelist(&["AAAA", "CCCC"], 2);
+        assert_eq!(whitelist.correct("AAAT"), Correction::Corrected("AAAA".to_string(), 1));
+        assert_eq!(whitelist.correct("AANA"), Correction::Corrected("AAAA".to_string(), 1));
+        assert_eq!(whitelist.correct("AATT"), Correction::Corrected("AAAA".to_string(), 2));
+    }
+
+    #[test]
+    fn ties_are_ambiguous_but_closer_entries_win() {
+        let whitelist = whitelist(&["AAAA", "AAAC", "GGCA", "GCTA"], 2);
+        // One substitution from both "AAAA" and "AAAC"
+        assert_eq!(whitelist.correct("AAAG"), Correction::Ambiguous);
+        // One substitution from "AAAA", and two from both "GGCA" and "GCTA"
+        assert_eq!(whitelist.correct("GAAA"), Correction::Corrected("AAAA".to_string(), 1));
+        // Two substitutions from both "GGCA" and "GCTA"
+        assert_eq!(whitelist.correct("GCCT"), Correction::Ambiguous);
+    }
+
+    #[test]
+    fn distant_barcodes_are_unmatched() {
+        assert_eq!(whitelist(&["AAAA"], 1).correct("AATT"), Correction::Unmatched);
+        assert_eq!(whitelist(&["AAAA"], 0).correct("AAAT"), Correction::Unmatched);
+    }
+
+    #[test]
+    fn columns_are_chosen_by_capture_group_name() {
+        let directory = tempfile::tempdir().expect("failed to create temporary directory");
+        let path = directory.path().join("whitelist.tsv");
+        fs::write(&path, "variant\tBC1\tBC\nA1\tAAAA\tCCCC\n").expect("failed to write whitelist");
+        assert_eq!(Whitelist::read(&path, "BC1", 0).expect("whitelist has a BC1 column").correct("AAAA"), Correction::Exact);
+        assert_eq!(Whitelist::read(&path, "BC2", 0).expect("whitelist has a BC column").correct("CCCC"), Correction::Exact);
+        fs::write(&path, "variant\tbarcode\nA1\tAAAA\n").expect("failed to write whitelist");
+        assert!(matches!(Whitelist::read(&path, "BC1", 0), Err(WhitelistError::MissingColumn(_))));
+        fs::write(&path, "# one barcode per line\nAAAA\n\nCCCC\n").expect("failed to write whitelist");
+        assert_eq!(Whitelist::read(&path, "BC1", 0).expect("whitelist has barcodes").barcodes.len(), 2);
+    }
+}
@@ -0,0 +1,304 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, fs, io::{self, prelude::*}, path};
+
+use itertools::Itertools;
+use rand::SeedableRng;
+use rand_distr::{Distribution, Hypergeometric};
+use serde::Serialize;
+
+use crate::umi;
+use crate::utils::table::{TableFormat, TableReadError, TableReader};
+
+/// Report how deeply a library was sequenced, from a table written by bcbuddy
+#[derive(clap::Args, Debug)]
+pub struct ComplexityArguments {
+    /// A barcode table written by bcbuddy, either with one row per read or, with `--count`, one
+    /// row per barcode with a "read_count" column. TSV tables may be gzip, BGZF or zstd compressed.
+    #[arg(short, long, value_parser = clap::value_parser!(path::PathBuf), value_hint = clap::ValueHint::FilePath)]
+    input: path::PathBuf,
+
+    /// The format of the input table. If not set, it is guessed from the input file's extension
+    /// (".parquet", or ".arrow", ".ipc" or ".feather" for Arrow IPC), defaulting to TSV.
+    #[arg(long, value_enum)]
+    input_format: Option<TableFormat>,
+
+    /// A path to a file in which the report should be written (JSON format)
+    #[arg(short, long, value_parser = clap::value_parser!(path::PathBuf), value_hint = clap::ValueHint::FilePath)]
+    output: path::PathBuf,
+
+    /// A path to a file in which the points of the saturation curve should be written (TSV format).
+    /// Each point draws its reads without replacement from the next larger subsample, so every
+    /// subsample contains the smaller ones. Only the read count of each barcode is held in memory.
+    #[arg(long, value_parser = clap::value_parser!(path::PathBuf), value_hint = clap::ValueHint::FilePath)]
+    curve: Option<path::PathBuf>,
+
+    /// The columns that together make up a barcode, separated by commas. Defaults to every column
+    /// other than the UMI and those bcbuddy adds alongside capture groups (read IDs, counts, edits,
+    /// strands, samples, and the whitelist corrections and qualities of capture groups in the table).
+    #[arg(short, long, value_delimiter = ',')]
+    columns: Vec<String>,
+
+    /// The number of evenly spaced subsampling depths at which the saturation curve is evaluated
+    #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u64).range(1..))]
+    points: u64,
+
+    /// The seed for drawing reads when subsampling, so that saturation curves are repeatable
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+/// The library complexity report, written as JSON
+#[derive(Serialize, Debug)]
+struct ComplexityReport {
+    columns: Vec<String>,
+    total_reads: u64,
+    unique_barcodes: usize,
+    /// Barcodes seen in exactly one read
+    singletons: usize,
+    /// The fraction of reads that repeat an already-seen barcode
+    saturation: f64,
+    mean_reads_per_barcode: f64,
+    median_reads_per_barcode: f64,
+    /// How unevenly reads are spread across barcodes, from 0 (every barcode has the same number of
+    /// reads) towards 1 (one barcode has nearly all of them)
+    gini_coefficient: f64,
+    /// The number of barcodes with each read count
+    count_distribution: BTreeMap<u64, usize>,
+}
+
+/// A point on the saturation curve, written to the curve TSV
+struct CurvePoint {
+    fraction: f64,
+    reads: u64,
+    unique_barcodes: usize,
+    saturation: f64,
+}
+
+pub fn run(arguments: ComplexityArguments) -> Result<(), Box<dyn std::error::Error>> {
+    let (columns, counts) = match read_counts(&arguments.input, arguments.input_format, &arguments.columns) {
+        Ok(table) => table,
+        Err(error) => { return Err(Box::new(error)); }
+    };
+
+    let mut sorted_counts: Vec<u64> = counts.into_values().collect();
+    sorted_counts.sort_unstable();
+    let total_reads: u64 = sorted_counts.iter().sum();
+    let unique_barcodes = sorted_counts.len();
+
+    let mut count_distribution: BTreeMap<u64, usize> = BTreeMap::new();
+    for count in sorted_counts.iter() {
+        *count_distribution.entry(*count).or_insert(0) += 1;
+    }
+
+    if let Some(curve_path) = arguments.curve {
+        let mut out = io::BufWriter::new(fs::File::create(curve_path)?);
+        writeln!(out, "fraction\treads\tunique_barcodes\tsaturation")?;
+        for point in saturation_curve(&sorted_counts, arguments.points, arguments.seed) {
+            writeln!(out, "{}\t{}\t{}\t{}", point.fraction, point.reads, point.unique_barcodes, point.saturation)?;
+        }
+        out.flush()?;
+    }
+
+    let report = ComplexityReport {
+        columns,
+        total_reads,
+        unique_barcodes,
+        singletons: count_distribution.get(&1).copied().unwrap_or(0),
+        saturation: saturation(unique_barcodes as f64, total_reads as f64),
+        mean_reads_per_barcode: if unique_barcodes > 0 { total_reads as f64 / unique_barcodes as f64 } else { 0.0 },
+        median_reads_per_barcode: median(&sorted_counts),
+        gini_coefficient: gini_coefficient(&sorted_counts),
+        count_distribution,
+    };
+    serde_json::to_writer_pretty(fs::File::create(&arguments.output)?, &report)?;
+    Ok(())
+}
+
+/// Whether a column is one bcbuddy writes alongside the capture groups, rather than a capture group.
+/// Columns derived from a capture group, like its whitelist correction, only count as such if the
+/// capture group is among the `columns` of the table, as are per-read columns for reads 1 to N.
+fn is_metadata_column(column: &str, columns: &HashSet<&str>) -> bool {
+    let is_read_column = column.strip_prefix("read").and_then(|rest| rest.split_once('_')).is_some_and(|(number, suffix)| {
+        ["id", "edits", "strand"].contains(&suffix) && number.parse::<usize>().is_ok_and(|number| {
+            number > 0 && column == format!("read{}_{}", number, suffix) && (1..number).all(|read| columns.contains(format!("read{}_{}", read, suffix).as_str()))
+        })
+    });
+    let is_correction_column = column.strip_prefix("uncorrected_").is_some_and(|group| columns.contains(group) && columns.contains(format!("{}_distance", group).as_str()))
+        || column.strip_suffix("_distance").is_some_and(|group| columns.contains(group) && columns.contains(format!("uncorrected_{}", group).as_str()));
+    let is_quality_column = column.strip_suffix("_mean_quality").is_some_and(|group| columns.contains(group));
+    is_read_column || is_correction_column || is_quality_column || [umi::UMI_GROUP_NAME, "sample", "read_count", "umi_count"].contains(&column)
+}
+
+/// Read a barcode table, returning the barcode columns and the number of reads for each barcode
+fn read_counts(path: &path::Path, format: Option<TableFormat>, requested_columns: &[String]) -> Result<(Vec<String>, HashMap<String, u64>), ComplexityError> {
+    let table = TableReader::open(path, format)?;
+    let header = table.columns().to_vec();
+
+    let barcode_columns: Vec<usize> = if requested_columns.is_empty() {
+        let columns: HashSet<&str> = header.iter().map(String::as_str).collect();
+        (0..header.len()).filter(|index| !is_metadata_column(&header[*index], &columns)).collect()
+    } else {
+        requested_columns.iter().map(|column| header.iter().position(|name| name == column).ok_or_else(|| ComplexityError::UnknownColumn(column.clone()))).collect::<Result<_, _>>()?
+    };
+    if barcode_columns.is_empty() {
+        return Err(ComplexityError::NoBarcodeColumns);
+    }
+    let count_column = header.iter().position(|name| name == "read_count");
+
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    for (row_number, fields) in table.enumerate() {
+        let fields = fields?;
+        let count = match count_column {
+            Some(count_column) => fields[count_column].parse::<u64>().map_err(|_| ComplexityError::InvalidCount(row_number + 1, fields[count_column].clone()))?,
+            None => 1,
+        };
+        let barcode = barcode_columns.iter().map(|column| fields[*column].as_str()).join("\t");
+        *counts.entry(barcode).or_insert(0) += count;
+    }
+
+    Ok((barcode_columns.iter().map(|column| header[*column].clone()).collect(), counts))
+}
+
+/// The fraction of reads that did not reveal a new barcode
+fn saturation(unique_barcodes: f64, reads: f64) -> f64 {
+    if reads > 0.0 { 1.0 - unique_barcodes / reads } else { 0.0 }
+}
+
+fn median(sorted_counts: &[u64]) -> f64 {
+    match sorted_counts.len() {
+        0 => 0.0,
+        length if length % 2 == 1 => sorted_counts[length / 2] as f64,
+        length => (sorted_counts[length / 2 - 1] + sorted_counts[length / 2]) as f64 / 2.0,
+    }
+}
+
+fn gini_coefficient(sorted_counts: &[u64]) -> f64 {
+    let total: f64 = sorted_counts.iter().map(|count| *count as f64).sum();
+    if sorted_counts.is_empty() || total == 0.0 {
+        return 0.0;
+    }
+    let length = sorted_counts.len() as f64;
+    let weighted_sum: f64 = sorted_counts.iter().enumerate().map(|(index, count)| (index + 1) as f64 * *count as f64).sum();
+    2.0 * weighted_sum / (length * total) - (length + 1.0) / length
+}
+
+/// The number of unique barcodes, and the resulting saturation, in subsamples of the reads at
+/// `num_points` evenly spaced fractions up to 1. Starting from all reads, each subsample is drawn
+/// without replacement from the next larger one, with a generator seeded with `seed`, so that the
+/// subsamples are nested without holding the reads themselves.
+fn saturation_curve(counts: &[u64], num_points: u64, seed: u64) -> Vec<CurvePoint> {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+    let total_reads: u64 = counts.iter().sum();
+    let mut subsample = counts.to_vec();
+    let mut depth = total_reads;
+    let mut curve: Vec<CurvePoint> = (1..=num_points).rev().map(|point| {
+        let next_depth = (total_reads as u128 * point as u128 / num_points as u128) as u64;
+        draw_without_replacement(&mut subsample, depth, next_depth, &mut rng);
+        depth = next_depth;
+        let unique_barcodes = subsample.iter().filter(|count| **count > 0).count();
+        CurvePoint {
+            fraction: point as f64 / num_points as f64,
+            reads: depth,
+            unique_barcodes,
+            saturation: saturation(unique_barcodes as f64, depth as f64),
+        }
+    }).collect();
+    curve.reverse();
+    curve
+}
+
+/// Replace the read `counts` of each barcode, which sum to `total`, with those of `draws` reads drawn
+/// from them without replacement. Each barcode's share follows a hypergeometric distribution given
+/// the reads and draws left after the barcodes before it.
+fn draw_without_replacement(counts: &mut [u64], total: u64, draws: u64, rng: &mut impl rand::Rng) {
+    let mut remaining_reads = total;
+    let mut remaining_draws = draws;
+    for count in counts.iter_mut() {
+        let drawn = match remaining_draws {
+            0 => 0,
+            _ if remaining_draws == remaining_reads => *count,
+            _ => Hypergeometric::new(remaining_reads, *count, remaining_draws).expect("draws are within the remaining reads").sample(rng),
+        };
+        remaining_reads -= *count;
+        remaining_draws -= drawn;
+        *count = drawn;
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ComplexityError {
+    #[error("failed to read barcode table")]
+    Reading {
+        #[from]
+        source: TableReadError
+    },
+    #[error("barcode table has no column \"{0}\"")]
+    UnknownColumn(String),
+    #[error("barcode table has no barcode columns; choose them with --columns")]
+    NoBarcodeColumns,
+    #[error("row {0} of barcode table has an invalid read count \"{1}\"")]
+    InvalidCount(usize, String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::table::{ColumnType, TableWriter, Value};
+
+    #[test]
+    fn default_barcode_columns_exclude_the_umi() {
+        let columns = ["read1_id", "read2_id", "BC1", "BC2", umi::UMI_GROUP_NAME, "BC1_mean_quality", "uncorrected_BC1", "BC1_distance", "read_count", "umi_count", "sample"];
+        let present: HashSet<&str> = columns.into_iter().collect();
+        assert_eq!(columns.into_iter().filter(|column| !is_metadata_column(column, &present)).collect::<Vec<_>>(), ["BC1", "BC2"]);
+    }
+
+    #[test]
+    fn capture_groups_named_like_metadata_are_kept() {
+        // None of these is derived from another column of the table
+        let columns = ["BC", "insert_distance", "uncorrected_BC", "tag_mean_quality", "read2_id", "read0_id"];
+        let present: HashSet<&str> = columns.into_iter().collect();
+        assert_eq!(columns.into_iter().filter(|column| !is_metadata_column(column, &present)).collect::<Vec<_>>(), columns);
+    }
+
+    #[test]
+    fn counts_are_read_from_columnar_tables() {
+        let directory = tempfile::tempdir().expect("failed to create temporary directory");
+        let columns = [("BC".to_string(), ColumnType::Text), ("read_count".to_string(), ColumnType::Integer)];
+        for (name, format) in [("counts.parquet", TableFormat::Parquet), ("counts.arrow", TableFormat::Arrow)] {
+            let path = directory.path().join(name);
+            // A small row group size splits the rows across several batches
+            let mut table = TableWriter::create(&path, &columns, None, None, 2).expect("failed to create table");
+            for (barcode, count) in [("AA", 3), ("CC", 1), ("AA", 2)] {
+                table.write_row(&[Value::Text(barcode.into()), Value::Integer(count)]).expect("failed to write row");
+            }
+            table.finish().expect("failed to finish table");
+
+            let (barcode_columns, counts) = read_counts(&path, Some(format), &[]).expect("failed to read table");
+            assert_eq!(barcode_columns, ["BC"]);
+            assert_eq!(counts, HashMap::from([("AA".to_string(), 5), ("CC".to_string(), 1)]));
+        }
+    }
+
+    #[test]
+    fn saturation_curve_subsamples_reads() {
+        let counts = [1, 1, 2, 6];
+        let curve = saturation_curve(&counts, 5, 7);
+        assert_eq!(curve.iter().map(|point| point.reads).collect::<Vec<_>>(), [2, 4, 6, 8, 10]);
+        assert!(curve.windows(2).all(|points| points[0].unique_barcodes <= points[1].unique_barcodes));
+        assert_eq!(curve.last().map(|point| point.unique_barcodes), Some(counts.len()));
+        // The same seed gives the same curve
+        let repeated = saturation_curve(&counts, 5, 7);
+        assert!(curve.iter().zip(repeated.iter()).all(|(point, repeated)| point.unique_barcodes == repeated.unique_barcodes));
+    }
+
+    #[test]
+    fn draws_are_taken_from_the_counts() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+        let counts = [5, 0, 1_000_000, 2];
+        for draws in [0, 1, 7, 500_000, 1_000_007] {
+            let mut subsample = counts;
+            draw_without_replacement(&mut subsample, counts.iter().sum(), draws, &mut rng);
+            assert_eq!(subsample.iter().sum::<u64>(), draws);
+            assert!(subsample.iter().zip(counts.iter()).all(|(drawn, count)| drawn <= count));
+        }
+    }
+}
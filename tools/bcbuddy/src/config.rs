@@ -0,0 +1,277 @@
+use std::{fs, io, path};
+
+use serde::Deserialize;
+
+use crate::barcodes::transform::Transform;
+use crate::utils::{compression::Compression, table::TableFormat};
+
+/// A run described in a TOML or YAML file, given with `--config` in place of the sources, regexes
+/// and outputs on the command line. Relative paths are relative to the directory containing the
+/// file. For example:
+///
+/// ```toml
+/// output = "barcodes.tsv"
+/// run_stats = "stats.json"
+///
+/// [[reads]]
+/// source = ["sample_L001_R1.fastq.gz", "sample_L002_R1.fastq.gz"]
+/// segments = [
+///     { capture = "BC1", length = 16 },
+///     { anchor = "AACTCTTACTGCCCAGTCCC" },
+///     { capture = "BC2", pattern = "[ATCG]{8}TG[ATCG]{8}", transforms = ["remove=8-10", "rc"] },
+/// ]
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RunConfig {
+    pub output: Option<path::PathBuf>,
+    pub output_compression: Option<Compression>,
+    pub output_format: Option<TableFormat>,
+    pub run_stats: Option<path::PathBuf>,
+    #[serde(default)]
+    pub reverse_complement_output: bool,
+    #[serde(default)]
+    pub both_strands: bool,
+    /// If set, every entry in `reads` must have the same source, from which consecutive records
+    /// are read as the mates of each read
+    #[serde(default)]
+    pub interleaved: bool,
+    /// One entry per source, in the same order as the output columns
+    pub reads: Vec<ReadConfig>,
+}
+
+/// Where one source's reads come from and the layout of the amplicon within them, given either as
+/// a regex or as a list of segments
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ReadConfig {
+    /// One or more files, read one after another (e.g. one per sequencing lane)
+    source: OneOrMany,
+    pub regex: Option<String>,
+    #[serde(default)]
+    pub segments: Vec<Segment>,
+    pub unmatched_reads: Option<path::PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum OneOrMany {
+    One(path::PathBuf),
+    Many(Vec<path::PathBuf>),
+}
+
+/// A consecutive part of an amplicon: exactly one of a constant `anchor` sequence, a named
+/// `capture` group (with an expected `length` or a regex `pattern`, and optionally `transforms`
+/// written as for `--transform`), or a number of bases to `skip`
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Segment {
+    pub anchor: Option<String>,
+    pub capture: Option<String>,
+    pub skip: Option<Length>,
+    pub length: Option<Length>,
+    pub pattern: Option<String>,
+    #[serde(default)]
+    pub transforms: Vec<String>,
+}
+
+/// A segment length, either exact or as an inclusive `[MIN, MAX]` range
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(untagged)]
+pub enum Length {
+    Exact(usize),
+    Between(usize, usize),
+}
+
+impl RunConfig {
+    /// Read and validate a configuration file, choosing TOML or YAML by its extension
+    pub fn read(path: &path::Path) -> Result<Self, ConfigError> {
+        let raw = fs::read_to_string(path)?;
+        let mut config: Self = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => toml::from_str(&raw)?,
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&raw)?,
+            _ => { return Err(ConfigError::UnknownFormat(path.display().to_string())); }
+        };
+        config.validate()?;
+        if let Some(directory) = path.parent() {
+            config.resolve_paths(directory);
+        }
+        Ok(config)
+    }
+
+    /// Make every relative path in the configuration relative to `directory` instead
+    fn resolve_paths(&mut self, directory: &path::Path) {
+        let resolve = |path: &mut path::PathBuf| *path = directory.join(&*path);
+        self.output.iter_mut().chain(self.run_stats.iter_mut()).for_each(resolve);
+        for read in self.reads.iter_mut() {
+            match read.source {
+                OneOrMany::One(ref mut source) => resolve(source),
+                OneOrMany::Many(ref mut sources) => sources.iter_mut().for_each(resolve),
+            }
+            read.unmatched_reads.iter_mut().for_each(resolve);
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.reads.is_empty() {
+            return Err(ConfigError::NoReads);
+        }
+        if self.reads.iter().any(|read| read.unmatched_reads.is_some()) && self.reads.iter().any(|read| read.unmatched_reads.is_none()) {
+            return Err(ConfigError::PartialUnmatchedReads);
+        }
+        let mut capture_group_names: Vec<String> = Vec::new();
+        for (index, read) in self.reads.iter().enumerate() {
+            if read.sources().is_empty() {
+                return Err(ConfigError::InvalidRead(index + 1, "no source files given".to_string()));
+            }
+            let regex = read.regex().map_err(|message| ConfigError::InvalidRead(index + 1, message))?;
+            let regex = regex::Regex::new(&regex).map_err(|error| ConfigError::InvalidRead(index + 1, error.to_string()))?;
+            for name in regex.capture_names().flatten() {
+                if capture_group_names.iter().any(|existing| existing == name) {
+                    return Err(ConfigError::DuplicateCaptureGroup(name.to_string()));
+                }
+                capture_group_names.push(name.to_string());
+            }
+            read.transforms().map_err(|message| ConfigError::InvalidRead(index + 1, message))?;
+        }
+        Ok(())
+    }
+}
+
+impl ReadConfig {
+    pub fn sources(&self) -> &[path::PathBuf] {
+        match &self.source {
+            OneOrMany::One(source) => std::slice::from_ref(source),
+            OneOrMany::Many(sources) => sources,
+        }
+    }
+
+    /// The regex matching this read's amplicon, either as given or built from its segments
+    pub fn regex(&self) -> Result<String, String> {
+        match (&self.regex, self.segments.is_empty()) {
+            (Some(regex), true) => Ok(regex.clone()),
+            (Some(_), false) => Err("only one of \"regex\" and \"segments\" may be given".to_string()),
+            (None, true) => Err("one of \"regex\" and \"segments\" must be given".to_string()),
+            (None, false) => {
+                self.segments.iter().enumerate().map(|(index, segment)| {
+                    segment.regex().map_err(|message| format!("segment {}: {}", index + 1, message))
+                }).collect()
+            }
+        }
+    }
+
+    /// The transforms given for each of this read's capture group segments
+    pub fn transforms(&self) -> Result<Vec<(String, Vec<Transform>)>, String> {
+        self.segments.iter().enumerate().filter(|(_, segment)| !segment.transforms.is_empty()).map(|(index, segment)| {
+            let name = segment.capture.as_ref().ok_or_else(|| format!("segment {}: only capture groups can have transforms", index + 1))?;
+            let transforms = segment.transforms.iter().map(|raw| raw.parse::<Transform>()).collect::<Result<Vec<_>, _>>().map_err(|message| format!("segment {}: {}", index + 1, message))?;
+            Ok((name.clone(), transforms))
+        }).collect()
+    }
+}
+
+impl Segment {
+    fn regex(&self) -> Result<String, String> {
+        match (&self.anchor, &self.capture, self.skip) {
+            (Some(anchor), None, None) => {
+                if self.length.is_some() || self.pattern.is_some() {
+                    return Err("an anchor cannot have a length or pattern".to_string());
+                }
+                if anchor.is_empty() || !anchor.bytes().all(|base| b"ACGTNacgtn".contains(&base)) {
+                    return Err(format!("anchor \"{}\" must be a non-empty sequence of A, C, G, T and N", anchor));
+                }
+                Ok(regex::escape(anchor))
+            },
+            (None, Some(name), None) => {
+                if name.is_empty() || !name.chars().all(|character| character.is_ascii_alphanumeric() || character == '_') || name.starts_with(|character: char| character.is_ascii_digit()) {
+                    return Err(format!("capture group name \"{}\" must be letters, digits and underscores, not starting with a digit", name));
+                }
+                let pattern = match (self.length, &self.pattern) {
+                    (Some(length), None) => format!("[ACGTN]{}", length.repetition()?),
+                    (None, Some(pattern)) => pattern.clone(),
+                    _ => { return Err(format!("capture group \"{}\" must have exactly one of a length and a pattern", name)); }
+                };
+                Ok(format!("(?P<{}>{})", name, pattern))
+            },
+            (None, None, Some(skip)) => {
+                if self.length.is_some() || self.pattern.is_some() {
+                    return Err("a skip cannot have a length or pattern".to_string());
+                }
+                Ok(format!(".{}", skip.repetition()?))
+            },
+            _ => Err("must have exactly one of \"anchor\", \"capture\" and \"skip\"".to_string()),
+        }
+    }
+}
+
+impl Length {
+    /// The regex repetition operator matching this length
+    fn repetition(&self) -> Result<String, String> {
+        match *self {
+            Self::Exact(0) => Err("length must be at least 1".to_string()),
+            Self::Exact(length) => Ok(format!("{{{}}}", length)),
+            Self::Between(min, max) if min > max || max == 0 => Err(format!("invalid length range [{}, {}]", min, max)),
+            Self::Between(min, max) => Ok(format!("{{{},{}}}", min, max)),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to read configuration file")]
+    Reading {
+        #[from]
+        source: io::Error
+    },
+    #[error("configuration file \"{0}\" must have a \".toml\", \".yaml\" or \".yml\" extension")]
+    UnknownFormat(String),
+    #[error("invalid TOML configuration")]
+    Toml {
+        #[from]
+        source: toml::de::Error
+    },
+    #[error("invalid YAML configuration")]
+    Yaml {
+        #[from]
+        source: serde_yaml::Error
+    },
+    #[error("configuration must describe at least one read")]
+    NoReads,
+    #[error("read {0}: {1}")]
+    InvalidRead(usize, String),
+    #[error("unmatched reads must be written for either every read or none")]
+    PartialUnmatchedReads,
+    #[error("capture group name \"{0}\" is used more than once; names must be unique across all reads")]
+    DuplicateCaptureGroup(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> RunConfig {
+        toml::from_str(raw).expect("valid TOML configuration")
+    }
+
+    #[test]
+    fn capture_group_names_must_be_unique_across_reads() {
+        let config = parse("[[reads]]\nsource = \"R1.fq\"\nregex = \"(?P<BC>..)\"\n[[reads]]\nsource = \"R2.fq\"\nsegments = [{ capture = \"BC\", length = 2 }]\n");
+        assert!(matches!(config.validate(), Err(ConfigError::DuplicateCaptureGroup(name)) if name == "BC"));
+    }
+
+    #[test]
+    fn relative_paths_are_relative_to_the_configuration() {
+        let mut config = parse("output = \"out.tsv\"\n[[reads]]\nsource = [\"lane,1.fq\", \"/data/lane2.fq\"]\nregex = \"(?P<BC>..)\"\nunmatched_reads = \"unmatched.fq\"\n");
+        config.validate().expect("valid configuration");
+        config.resolve_paths(path::Path::new("runs"));
+        assert_eq!(config.output, Some(path::PathBuf::from("runs/out.tsv")));
+        assert_eq!(config.reads[0].sources(), [path::PathBuf::from("runs/lane,1.fq"), path::PathBuf::from("/data/lane2.fq")]);
+        assert_eq!(config.reads[0].unmatched_reads, Some(path::PathBuf::from("runs/unmatched.fq")));
+    }
+
+    #[test]
+    fn transforms_are_only_allowed_on_capture_groups() {
+        let config = parse("[[reads]]\nsource = \"R1.fq\"\nsegments = [{ anchor = \"ACGT\", transforms = [\"rc\"] }, { capture = \"BC\", length = 2 }]\n");
+        assert!(matches!(config.validate(), Err(ConfigError::InvalidRead(1, _))));
+    }
+}
@@ -0,0 +1,202 @@
+use std::{cmp, collections::{BinaryHeap, HashMap}, fs, io::{self, prelude::*}, path};
+
+use crate::{umi, utils::table};
+
+/// A sorted sequence of (key, count) pairs, either spilled to disk or still in memory
+type CountRun = Box<dyn Iterator<Item = io::Result<(String, usize)>>>;
+
+/// Counts the reads seen for each unique barcode key (the tab-joined capture groups of a read).
+///
+/// Counts are kept in memory until the number of unique keys exceeds a limit, at which point they
+/// are sorted and spilled to an anonymous temporary file. The spilled runs are merged when the
+/// final table is written.
+pub struct BarcodeCounter {
+    counts: HashMap<String, usize>,
+    max_keys_in_memory: usize,
+    spill_directory: path::PathBuf,
+    spilled_runs: Vec<fs::File>,
+}
+
+impl BarcodeCounter {
+    pub fn new(max_keys_in_memory: usize, spill_directory: path::PathBuf) -> Self {
+        Self {
+            counts: HashMap::new(),
+            max_keys_in_memory,
+            spill_directory,
+            spilled_runs: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, key: String) -> io::Result<()> {
+        *self.counts.entry(key).or_insert(0) += 1;
+        if self.counts.len() > self.max_keys_in_memory {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        let mut run = io::BufWriter::new(tempfile::tempfile_in(&self.spill_directory)?);
+        for (key, count) in self.drain_sorted() {
+            writeln!(run, "{}\t{}", key, count)?;
+        }
+        let mut run = run.into_inner().map_err(|error| error.into_error())?;
+        run.rewind()?;
+        self.spilled_runs.push(run);
+        Ok(())
+    }
+
+    fn drain_sorted(&mut self) -> Vec<(String, usize)> {
+        let mut counts: Vec<(String, usize)> = self.counts.drain().collect();
+        counts.sort_unstable();
+        counts
+    }
+
+    /// Write every key and its count to a table, sorted by key. Each tab-separated field of the key
+    /// is written as a separate column.
+    pub fn write_sorted(self, out: &mut table::TableWriter) -> Result<(), table::TableError> {
+        let num_key_columns = out.num_columns() - 1;
+        self.for_each_sorted(|key, count| {
+            out.write_row(&key_values(&key, num_key_columns).chain([table::Value::Integer(count as u64)]).collect::<Vec<_>>())
+        })
+    }
+
+    /// Write every key with its final field (a UMI) removed to a table, along with its total read
+    /// count and the number of distinct molecules among its UMIs, sorted by key
+    pub fn write_sorted_with_umis(self, out: &mut table::TableWriter, correction: umi::UmiCorrection) -> Result<(), table::TableError> {
+        let num_key_columns = out.num_columns() - 2;
+        let mut current_key: Option<String> = None;
+        let mut umis: Vec<(String, usize)> = Vec::new();
+        let mut write_current = |key: &str, umis: &[(String, usize)]| -> Result<(), table::TableError> {
+            let read_count: usize = umis.iter().map(|(_, count)| count).sum();
+            let counts = [table::Value::Integer(read_count as u64), table::Value::Integer(umi::count_molecules(umis, correction) as u64)];
+            out.write_row(&key_values(key, num_key_columns).chain(counts).collect::<Vec<_>>())
+        };
+        self.for_each_sorted(|key_with_umi, count| {
+            let (key, umi) = key_with_umi.rsplit_once('\t').unwrap_or(("", key_with_umi.as_str()));
+            if current_key.as_deref() != Some(key) {
+                if let Some(current_key) = current_key.as_deref() {
+                    write_current(current_key, &umis)?;
+                }
+                current_key = Some(key.to_string());
+                umis.clear();
+            }
+            umis.push((umi.to_string(), count));
+            Ok::<(), table::TableError>(())
+        })?;
+        if let Some(current_key) = current_key.as_deref() {
+            write_current(current_key, &umis)?;
+        }
+        Ok(())
+    }
+
+    /// Call `each` with every key and its count, sorted by key. Keys with the same value that were
+    /// spilled separately are combined.
+    fn for_each_sorted<E: From<io::Error>>(mut self, mut each: impl FnMut(String, usize) -> Result<(), E>) -> Result<(), E> {
+        let mut runs: Vec<CountRun> = Vec::new();
+        for run in self.spilled_runs.drain(..) {
+            runs.push(Box::new(io::BufReader::new(run).lines().map(|line| {
+                let line = line?;
+                let (key, count) = line.rsplit_once('\t').ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed spilled barcode count"))?;
+                let count = count.parse::<usize>().map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+                Ok((key.to_string(), count))
+            })));
+        }
+        runs.push(Box::new(self.drain_sorted().into_iter().map(Ok)));
+
+        // k-way merge of the sorted runs, smallest key first
+        let mut heads: BinaryHeap<cmp::Reverse<(String, usize, usize)>> = BinaryHeap::new();
+        for (index, run) in runs.iter_mut().enumerate() {
+            if let Some(head) = run.next() {
+                let (key, count) = head?;
+                heads.push(cmp::Reverse((key, index, count)));
+            }
+        }
+
+        let mut current: Option<(String, usize)> = None;
+        while let Some(cmp::Reverse((key, index, count))) = heads.pop() {
+            match current {
+                Some((ref current_key, ref mut current_count)) if *current_key == key => {
+                    *current_count += count;
+                },
+                _ => {
+                    if let Some((current_key, current_count)) = current.take() {
+                        each(current_key, current_count)?;
+                    }
+                    current = Some((key, count));
+                }
+            }
+            if let Some(head) = runs[index].next() {
+                let (key, count) = head?;
+                heads.push(cmp::Reverse((key, index, count)));
+            }
+        }
+        if let Some((current_key, current_count)) = current {
+            each(current_key, current_count)?;
+        }
+        Ok(())
+    }
+}
+
+/// The table values for the fields of a key. A key with no fields is empty, which is otherwise
+/// indistinguishable from a key with a single empty field.
+fn key_values(key: &str, num_fields: usize) -> impl Iterator<Item = table::Value<'_>> {
+    key.split('\t').take(num_fields).map(|field| table::Value::Text(field.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted_counts(counter: BarcodeCounter) -> Vec<(String, usize)> {
+        let mut counts = Vec::new();
+        counter.for_each_sorted(|key, count| {
+            counts.push((key, count));
+            Ok::<(), io::Error>(())
+        }).expect("failed to merge counts");
+        counts
+    }
+
+    #[test]
+    fn counts_are_sorted_without_spilling() {
+        let mut counter = BarcodeCounter::new(10, std::env::temp_dir());
+        for key in ["GG", "AA", "GG", "CC"] {
+            counter.add(key.to_string()).expect("failed to count key");
+        }
+        assert!(counter.spilled_runs.is_empty());
+        assert_eq!(sorted_counts(counter), vec![("AA".to_string(), 1), ("CC".to_string(), 1), ("GG".to_string(), 2)]);
+    }
+
+    #[test]
+    fn keys_are_combined_across_spilled_runs() {
+        let spill_directory = tempfile::tempdir().expect("failed to create temporary directory");
+        let mut counter = BarcodeCounter::new(2, spill_directory.path().to_path_buf());
+        // Spills after the third distinct key each time, so "AA" and "TT" end up in several runs
+        for key in ["AA", "TT", "CC", "AA", "GG", "TT", "AA", "CA", "TT", "TT"] {
+            counter.add(key.to_string()).expect("failed to count key");
+        }
+        assert!(counter.spilled_runs.len() >= 2);
+        assert_eq!(sorted_counts(counter), vec![
+            ("AA".to_string(), 3),
+            ("CA".to_string(), 1),
+            ("CC".to_string(), 1),
+            ("GG".to_string(), 1),
+            ("TT".to_string(), 4),
+        ]);
+    }
+
+    #[test]
+    fn umis_are_grouped_by_key_across_spilled_runs() {
+        let directory = tempfile::tempdir().expect("failed to create temporary directory");
+        let mut counter = BarcodeCounter::new(1, directory.path().to_path_buf());
+        for key in ["AA\tUMI1", "CC\tUMI1", "AA\tUMI2", "AA\tUMI1"] {
+            counter.add(key.to_string()).expect("failed to count key");
+        }
+        let path = directory.path().join("counts.tsv");
+        let columns = [("BC".to_string(), table::ColumnType::Text), ("read_count".to_string(), table::ColumnType::Integer), ("umi_count".to_string(), table::ColumnType::Integer)];
+        let mut out = table::TableWriter::create(&path, &columns, None, None, 1024).expect("failed to create table");
+        counter.write_sorted_with_umis(&mut out, umi::UmiCorrection::None).expect("failed to write counts");
+        out.finish().expect("failed to finish table");
+        assert_eq!(fs::read_to_string(&path).expect("failed to read table"), "BC\tread_count\tumi_count\nAA\t3\t2\nCC\t1\t1\n");
+    }
+}
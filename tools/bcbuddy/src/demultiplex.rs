@@ -0,0 +1,226 @@
+use std::{fs, io::{self, prelude::*}, path};
+
+/// The name given to reads that cannot be assigned to exactly one sample
+pub const UNDETERMINED: &str = "undetermined";
+
+/// Where the index sequence for a sample sheet column comes from
+#[derive(Debug)]
+enum IndexSource {
+    /// An extracted capture group (an inline barcode), by its position in the output columns
+    CaptureGroup(usize),
+    /// The i7 (0) or i5 (1) index in the comment of an Illumina read header, e.g.
+    /// `1:N:0:ACGTACGT+TTGCAAGG`
+    ReadHeader(usize),
+}
+
+/// Assigns reads to samples by comparing index barcodes to those listed in a sample sheet.
+///
+/// The sample sheet is a tab-separated file with a header. Its first column is "sample" and holds
+/// sample names; every other column holds the index sequence for each sample and is named either
+/// after a capture group, for inline barcodes, or "i7" or "i5", for indexes in the read header.
+/// Sample names may be used in output paths, so they cannot be empty or contain "/", "\\" or "..".
+pub struct SampleSheet {
+    index_sources: Vec<IndexSource>,
+    samples: Vec<(String, Vec<String>)>,
+    max_mismatches: usize,
+}
+
+impl SampleSheet {
+    pub fn read(path: &path::Path, capture_group_names: &[&String], max_mismatches: usize) -> Result<Self, DemultiplexError> {
+        let mut lines = io::BufReader::new(fs::File::open(path)?).lines().filter(|line| {
+            !matches!(line, Ok(line) if line.trim().is_empty() || line.starts_with('#'))
+        });
+
+        let header = lines.next().ok_or(DemultiplexError::MissingHeader)??;
+        let mut columns = header.trim_end().split('\t');
+        if columns.next() != Some("sample") {
+            return Err(DemultiplexError::MissingHeader);
+        }
+        let index_sources = columns.map(|column| {
+            match column {
+                "i7" => Ok(IndexSource::ReadHeader(0)),
+                "i5" => Ok(IndexSource::ReadHeader(1)),
+                _ => {
+                    capture_group_names.iter().position(|name| name.as_str() == column).map(IndexSource::CaptureGroup).ok_or_else(|| DemultiplexError::UnknownIndex(column.to_string()))
+                }
+            }
+        }).collect::<Result<Vec<_>, _>>()?;
+        if index_sources.is_empty() {
+            return Err(DemultiplexError::MissingHeader);
+        }
+
+        let mut samples: Vec<(String, Vec<String>)> = Vec::new();
+        for line in lines {
+            let line = line?;
+            let mut fields = line.trim_end().split('\t');
+            let name = fields.next().unwrap_or("").to_string();
+            let indexes: Vec<String> = fields.map(|index| index.to_ascii_uppercase()).collect();
+            if indexes.len() != index_sources.len() {
+                return Err(DemultiplexError::MalformedSample(name, index_sources.len(), indexes.len()));
+            }
+            // Sample names can become part of output paths, so they must not leave the output directory
+            if name.is_empty() || name.contains(['/', '\\', '\0']) || name.contains("..") {
+                return Err(DemultiplexError::InvalidSampleName(name));
+            }
+            if name == UNDETERMINED || samples.iter().any(|(existing_name, _)| *existing_name == name) {
+                return Err(DemultiplexError::DuplicateSample(name));
+            }
+            samples.push((name, indexes));
+        }
+
+        Ok(Self {
+            index_sources,
+            samples,
+            max_mismatches,
+        })
+    }
+
+    pub fn sample_names(&self) -> impl Iterator<Item = &str> {
+        self.samples.iter().map(|(name, _)| name.as_str())
+    }
+
+    pub fn sample_name(&self, sample: usize) -> &str {
+        &self.samples[sample].0
+    }
+
+    pub fn num_samples(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Find the sample a read belongs to, given its (transformed) capture groups and the identifier
+    /// of its first mate. Each index may differ from the sample sheet by up to the allowed number of
+    /// mismatches; the sample with the fewest total mismatches is chosen. Returns `None` if no
+    /// sample matches, or if several match equally well.
+    pub fn assign(&self, captures: &[String], identifier: &str) -> Option<usize> {
+        let header_indexes: Vec<&str> = identifier.split_ascii_whitespace().nth(1)
+            .and_then(|comment| comment.rsplit(':').next())
+            .map_or_else(Vec::new, |indexes| indexes.split('+').collect());
+        let observed: Vec<Option<&str>> = self.index_sources.iter().map(|source| {
+            match source {
+                IndexSource::CaptureGroup(position) => captures.get(*position).map(String::as_str),
+                IndexSource::ReadHeader(position) => header_indexes.get(*position).copied(),
+            }
+        }).collect();
+
+        let mut best: Option<(usize, usize)> = None;
+        let mut tied = false;
+        for (sample, (_, indexes)) in self.samples.iter().enumerate() {
+            let distances: Option<Vec<usize>> = indexes.iter().zip(observed.iter()).map(|(expected, observed)| {
+                observed.and_then(|observed| hamming_distance(expected, observed)).filter(|distance| *distance <= self.max_mismatches)
+            }).collect();
+            let distance: usize = match distances {
+                Some(distances) => distances.iter().sum(),
+                None => { continue; }
+            };
+            match best {
+                Some((_, best_distance)) if distance > best_distance => {},
+                Some((_, best_distance)) if distance == best_distance => { tied = true; },
+                _ => {
+                    best = Some((sample, distance));
+                    tied = false;
+                }
+            }
+        }
+
+        if tied { None } else { best.map(|(sample, _)| sample) }
+    }
+}
+
+/// The number of mismatching positions (ignoring case; `N` always mismatches) between two
+/// sequences, or `None` if they have different lengths
+fn hamming_distance(expected: &str, observed: &str) -> Option<usize> {
+    if expected.len() != observed.len() {
+        return None;
+    }
+    Some(expected.bytes().zip(observed.bytes()).filter(|(expected, observed)| {
+        !expected.eq_ignore_ascii_case(observed) || observed.eq_ignore_ascii_case(&b'N')
+    }).count())
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum DemultiplexError {
+    #[error("failed to read sample sheet")]
+    Reading {
+        #[from]
+        source: io::Error
+    },
+    #[error("sample sheet must start with a header line of \"sample\" followed by one or more index columns")]
+    MissingHeader,
+    #[error("sample sheet index column \"{0}\" is neither \"i7\", \"i5\" nor a capture group name")]
+    UnknownIndex(String),
+    #[error("sample \"{0}\" should have {1} indexes but has {2}")]
+    MalformedSample(String, usize, usize),
+    #[error("sample name \"{0}\" is duplicated or reserved")]
+    DuplicateSample(String),
+    #[error("sample name \"{0}\" is empty or contains a path separator or \"..\"")]
+    InvalidSampleName(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A sample sheet matching the capture group "BC" and the i7 index of the read header
+    fn sample_sheet(max_mismatches: usize) -> SampleSheet {
+        SampleSheet {
+            index_sources: vec![IndexSource::CaptureGroup(0), IndexSource::ReadHeader(0)],
+            samples: vec![
+                ("a".to_string(), vec!["AAAA".to_string(), "CCCC".to_string()]),
+                ("b".to_string(), vec!["AAAT".to_string(), "GGGG".to_string()]),
+                ("c".to_string(), vec!["AATA".to_string(), "GGGG".to_string()]),
+            ],
+            max_mismatches,
+        }
+    }
+
+    fn assign<'a>(sample_sheet: &'a SampleSheet, barcode: &str, i7: &str) -> Option<&'a str> {
+        let sample = sample_sheet.assign(&[barcode.to_string()], &format!("read1 1:N:0:{}+TTGCAAGG", i7))?;
+        Some(sample_sheet.sample_name(sample))
+    }
+
+    #[test]
+    fn reads_are_assigned_to_the_closest_sample() {
+        let sample_sheet = sample_sheet(1);
+        assert_eq!(assign(&sample_sheet, "AAAA", "CCCC"), Some("a"));
+        assert_eq!(assign(&sample_sheet, "aaaa", "cccc"), Some("a"));
+        // One mismatch in each index
+        assert_eq!(assign(&sample_sheet, "AATC", "GGCG"), Some("c"));
+        // Too many mismatches in one index
+        assert_eq!(assign(&sample_sheet, "TTAA", "GGGG"), None);
+        assert_eq!(assign(&sample_sheet, "AAAA", "CCGG"), None);
+        // Different lengths never match
+        assert_eq!(assign(&sample_sheet, "AAAAA", "CCCC"), None);
+        // Reads without an index in their header are undetermined
+        assert_eq!(sample_sheet.assign(&["AAAA".to_string()], "read1"), None);
+    }
+
+    #[test]
+    fn ties_are_undetermined() {
+        let sample_sheet = sample_sheet(2);
+        // Both "b" and "c" are within the allowed mismatches, but "b" has fewer
+        assert_eq!(assign(&sample_sheet, "AAAT", "GGGG"), Some("b"));
+        // One mismatch from both "b" and "c"
+        assert_eq!(assign(&sample_sheet, "AAAA", "GGGG"), None);
+    }
+
+    #[test]
+    fn n_always_mismatches() {
+        assert_eq!(hamming_distance("ACGN", "ACGN"), Some(1));
+        assert_eq!(hamming_distance("ACGT", "ACgN"), Some(1));
+        assert_eq!(assign(&sample_sheet(0), "AAAN", "CCCC"), None);
+        assert_eq!(assign(&sample_sheet(1), "AAAN", "CCCC"), Some("a"));
+    }
+
+    #[test]
+    fn sample_names_must_stay_within_the_output_directory() {
+        let directory = tempfile::tempdir().expect("failed to create temporary directory");
+        let path = directory.path().join("samples.tsv");
+        let barcode = "BC".to_string();
+        for name in ["", "../a", "a/b", "..", "a\\b"] {
+            fs::write(&path, format!("sample\tBC\n{}\tACGT\n", name)).expect("failed to write sample sheet");
+            assert!(matches!(SampleSheet::read(&path, &[&barcode], 0), Err(DemultiplexError::InvalidSampleName(invalid)) if invalid == name), "{:?} is accepted", name);
+        }
+        fs::write(&path, "sample\tBC\nsample.1\tACGT\n").expect("failed to write sample sheet");
+        assert_eq!(SampleSheet::read(&path, &[&barcode], 0).expect("valid sample sheet").sample_names().collect::<Vec<_>>(), ["sample.1"]);
+    }
+}
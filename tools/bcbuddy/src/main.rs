@@ -1,129 +1,695 @@
 use std::fs;
 use std::io::prelude::*;
+use std::time;
 
-use clap::{Parser, value_parser, CommandFactory};
+use clap::{Parser, value_parser, CommandFactory, FromArgMatches};
 use itertools::Itertools;
 
 mod barcodes;
+mod complexity;
+mod config;
+mod counting;
+mod demultiplex;
+mod pipeline;
+mod quality;
+mod stats;
+mod umi;
 mod utils;
 
 #[derive(Parser, Debug)]
-#[clap(author, about, version)]
+#[clap(author, about, version, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Arguments {
-    /// A FASTQ file containing amplicon reads from which to extract barcodes
-    #[arg(short, long, required = true, value_parser = value_parser!(std::path::PathBuf), value_hint = clap::ValueHint::FilePath)]
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// A TOML or YAML file describing the run: each read's source files, amplicon layout (as a
+    /// regex, or as a list of constant anchors, capture groups and skipped bases) and unmatched read
+    /// output, along with the output paths and orientation flags. Replaces `--source`, `--regex` and
+    /// `--unmatched-reads`; other options given on the command line take precedence.
+    #[arg(long, value_parser = value_parser!(std::path::PathBuf), value_hint = clap::ValueHint::FilePath, conflicts_with_all = ["source", "regex", "unmatched_reads"])]
+    config: Option<std::path::PathBuf>,
+
+    /// A file containing amplicon reads from which to extract barcodes. May be FASTQ, FASTA, SAM or
+    /// BAM (including unaligned BAM), detected from the file's contents, and gzip, BGZF or zstd
+    /// compressed. Given once per source; several files (e.g. one per sequencing lane) can follow a
+    /// single `--source`, in which case they are read one after another and must share a format.
+    /// Secondary and supplementary alignments are skipped, and reads aligned to the reverse strand
+    /// are reverse-complemented back to how they were sequenced.
+    #[arg(short, long, required_unless_present = "config", num_args = 1.., value_name = "PATH", value_parser = value_parser!(std::path::PathBuf), value_hint = clap::ValueHint::FilePath)]
     source: Vec<std::path::PathBuf>,
 
-    /// A path to a file in which extracted barcodes should be written.
-    #[arg(short, long, value_parser = value_parser!(std::path::PathBuf), value_hint = clap::ValueHint::FilePath)]
-    output: std::path::PathBuf,
+    /// If set, a single source holds all mates of each read as consecutive records (e.g.
+    /// interleaved paired-end FASTQ). Each set of records, one per `--regex` or `--positions`, is
+    /// read together and matched in order.
+    #[arg(long)]
+    interleaved: bool,
+
+    /// A path to a file in which extracted barcodes should be written. When demultiplexing with
+    /// `--sample-sheet`, the path may contain "{sample}", which is replaced by each sample name (and
+    /// "undetermined") to write one file per sample; otherwise, a "sample" column is added.
+    #[arg(short, long, required_unless_present = "config", value_parser = value_parser!(std::path::PathBuf), value_hint = clap::ValueHint::FilePath)]
+    output: Option<std::path::PathBuf>,
+
+    /// The compression to apply to the output file. If not set, it is guessed from the output
+    /// file's extension (".gz", ".bgz" or ".zst"), defaulting to uncompressed. For Parquet output,
+    /// this is applied to each column instead and defaults to zstd; Arrow IPC output cannot be
+    /// compressed.
+    #[arg(long, value_enum)]
+    output_compression: Option<utils::compression::Compression>,
+
+    /// The format of the output file. If not set, it is guessed from the output file's extension
+    /// (".parquet", or ".arrow", ".ipc" or ".feather" for Arrow IPC), defaulting to TSV. Columnar
+    /// formats store read counts, edits and qualities as numbers rather than text.
+    #[arg(long, value_enum)]
+    output_format: Option<utils::table::TableFormat>,
+
+    /// The maximum number of rows in each Parquet row group, or in each Arrow IPC record batch.
+    /// Rows are held in memory until a full group is written.
+    #[arg(long, default_value_t = 1_048_576, value_parser = clap::value_parser!(u64).range(1..))]
+    row_group_size: u64,
 
     /// The regex string matching the barcode(s). Should contain one or more
     /// capture groups
-    #[arg(short, long, required = true)]
+    #[arg(short, long, required_unless_present_any = ["config", "positions"])]
     regex: Vec<String>,
 
+    /// Extract capture groups at fixed positions instead of with a regex, given once per source as
+    /// `[ANCHOR@]NAME:START-END[,NAME:START-END...]`. Offsets are 0-based and end-exclusive, and
+    /// count from the start of the read or, if given, from the start of the first exact occurrence
+    /// of the constant anchor sequence, in which case they may be negative. Reads that are too
+    /// short, or lack the anchor, do not match.
+    #[arg(short('p'), long, conflicts_with_all = ["regex", "config", "max_anchor_edits"])]
+    positions: Vec<String>,
+
     /// If set, the returned capture groups will be reverse-complemented. This occurs *after* regex
-    /// matching.
-    #[arg(short, long)]
+    /// matching, and after any `--transform`s. The short flag is `-c`, as `-r` is `--regex`.
+    #[arg(short('c'), long)]
     reverse_complement_output: bool,
 
+    /// Transforms applied to a named capture group before it is written, as
+    /// GROUP:TRANSFORM[,TRANSFORM...]. Transforms are applied in order and may be "rc" (reverse
+    /// complement), "upper" (convert to uppercase), "trim-start=N" or "trim-end=N" (remove N bases
+    /// from the start or end), or "remove=START-END[+START-END...]" (remove the bases in 0-based,
+    /// end-exclusive ranges, all relative to the sequence before that transform; e.g. to drop
+    /// fixed spacers). May be given multiple times, and applied after any transforms given for the
+    /// group in a `--config` file. Reads whose capture groups cannot be transformed (e.g.
+    /// reverse-complemented, if they contain IUPAC bases other than N) are counted as failing
+    /// transforms in the run statistics and written with the unmatched reads.
+    #[arg(short('x'), long, value_parser = barcodes::transform::parse_group_transforms)]
+    transform: Vec<(String, Vec<barcodes::transform::Transform>)>,
+
+    /// A whitelist of the barcodes expected for a named capture group, as GROUP:PATH, used to
+    /// correct sequencing errors after any transforms. The file has one barcode per line, or is
+    /// tab-separated with a header, in which case the column named after the group (or "BC") is
+    /// used. Each barcode not in the whitelist is replaced by the unique entry nearest to it, if
+    /// any; the "uncorrected_GROUP" and "GROUP_distance" columns hold the original barcode and its
+    /// distance to the whitelist ("ambiguous" or "unmatched" if it was not corrected). May be given
+    /// once per capture group.
+    #[arg(short('w'), long, value_parser = barcodes::whitelist::parse_group_whitelist, value_name = "GROUP:PATH")]
+    whitelist: Vec<(String, std::path::PathBuf)>,
+
+    /// The maximum Hamming distance at which a barcode is corrected to a whitelist entry
+    #[arg(long, default_value_t = 1, requires = "whitelist")]
+    whitelist_distance: usize,
+
+    /// If set, when a regex does not match a read, it is also tried against the read's reverse
+    /// complement. The strand each regex matched on ("+" or "-") is written as an extra column.
+    #[arg(short('b'), long)]
+    both_strands: bool,
+
+    /// The number of edits allowed in each constant anchor (a literal sequence outside of any
+    /// capture group) of a regex when it does not match exactly. Capture groups are then extracted
+    /// by their position relative to the anchors, and the number of edits needed for each read is
+    /// written as an extra column. The rest of the regex must match a fixed length. If 0, only
+    /// exact matches are accepted.
+    #[arg(short('k'), long, default_value_t = 0)]
+    max_anchor_edits: usize,
+
+    /// If set, approximate matches may also have insertions and deletions inside anchors, not just
+    /// mismatches
+    #[arg(long, requires = "max_anchor_edits")]
+    allow_indels: bool,
+
+    /// The minimum Phred quality score required for every base inside a capture group. See
+    /// `--low-quality-action` for what happens to bases below it. Cannot be used with FASTA
+    /// sources; SAM or BAM records without quality scores (`*`) fail quality filtering.
+    #[arg(short('q'), long)]
+    min_base_quality: Option<u8>,
+
+    /// What to do when a captured base falls below `--min-base-quality`: discard the read, or
+    /// replace the base with N
+    #[arg(long, value_enum, default_value_t = quality::LowQualityAction::Discard, requires = "min_base_quality")]
+    low_quality_action: quality::LowQualityAction,
+
+    /// The maximum number of expected errors (the sum of the per-base error probabilities implied
+    /// by the quality scores) allowed in any single capture group. Reads with a capture group above
+    /// this are discarded.
+    #[arg(short('e'), long)]
+    max_expected_errors: Option<f64>,
+
+    /// A tab-separated sample sheet used to assign reads to samples. Its header must be "sample"
+    /// followed by one or more index columns, each named after a capture group (for inline
+    /// barcodes) or "i7"/"i5" (for indexes in Illumina read headers), and each row gives a sample
+    /// name and its index sequences. Capture groups are compared after any transforms. Reads that
+    /// match no sample, or several equally well, are assigned to "undetermined".
+    #[arg(long, value_parser = value_parser!(std::path::PathBuf), value_hint = clap::ValueHint::FilePath)]
+    sample_sheet: Option<std::path::PathBuf>,
+
+    /// The number of mismatches allowed in each index when assigning reads to samples
+    #[arg(long, default_value_t = 1, requires = "sample_sheet")]
+    index_mismatches: usize,
+
+    /// If set, the mean Phred quality score of each capture group (before any transforms) is
+    /// written in a "GROUP_mean_quality" column after it
+    #[arg(long, conflicts_with = "count")]
+    output_quality: bool,
+
     /// If set, FASTQ read IDs will be printed as a column in the output
-    #[arg(short('i'), long)]
+    #[arg(short('i'), long, conflicts_with = "count")]
     output_read_ids: bool,
 
+    /// If set, instead of one row per read, the output has one row per unique combination of
+    /// capture groups, with the number of reads having it in a final "read_count" column. Rows are
+    /// sorted by their capture groups. If a capture group is named "UMI", it is not written as a
+    /// column; instead, a "umi_count" column gives the number of distinct molecules (UMIs) seen for
+    /// each combination of the other capture groups.
+    #[arg(long)]
+    count: bool,
+
+    /// How UMIs that differ by sequencing errors are merged before counting molecules with
+    /// `--count`
+    #[arg(long, value_enum, default_value_t = umi::UmiCorrection::None, requires = "count")]
+    umi_correction: umi::UmiCorrection,
+
+    /// The number of unique capture group combinations held in memory with `--count` before they
+    /// are spilled to a temporary file
+    #[arg(long, default_value_t = 10_000_000, requires = "count")]
+    max_barcodes_in_memory: usize,
+
+    /// The directory in which temporary files are created with `--count`. Defaults to the system
+    /// temporary directory.
+    #[arg(long, value_parser = value_parser!(std::path::PathBuf), value_hint = clap::ValueHint::DirPath, requires = "count")]
+    temp_dir: Option<std::path::PathBuf>,
+
     /// A path to a file in which run statistics should be written (JSON format)
     #[arg(short('t'), long, value_parser = value_parser!(std::path::PathBuf), value_hint = clap::ValueHint::FilePath)]
     run_stats: Option<std::path::PathBuf>,
 
-    /*
     /// A path to a file in which reads that do not match the regex should be written (FASTQ
-    /// format)
+    /// format, or FASTA for reads without quality scores). If used, must be given once per source.
+    /// When any read in a set of mates fails to match, all of its mates are written as well, so the
+    /// unmatched files stay in sync. Output is compressed according to the file's extension.
     #[arg(short, long, value_parser = value_parser!(std::path::PathBuf), value_name = "PATH", value_hint = clap::ValueHint::FilePath)]
     unmatched_reads: Vec<std::path::PathBuf>,
-    */
+
+    /// A path to a file in which a summary of why reads did not match each regex should be
+    /// written (TSV format): how many failing reads first failed at each segment of the pattern
+    /// (constant anchors, capture groups and other patterns), how closely each anchor aligns to
+    /// them, and their most common leading sequences (e.g. primer dimers or adapter read-through).
+    #[arg(long, value_parser = value_parser!(std::path::PathBuf), value_hint = clap::ValueHint::FilePath, conflicts_with = "positions")]
+    failure_diagnostics: Option<std::path::PathBuf>,
+
+    /// The number of failing reads from each source analysed for `--failure-diagnostics`, taken
+    /// from the start of the input
+    #[arg(long, default_value_t = 10_000, requires = "failure_diagnostics")]
+    diagnostics_sample: usize,
+
+    /// What to do when the reads taken together from each source do not have matching names
+    /// (ignoring "/1"-style suffixes and anything after the first whitespace)
+    #[arg(long, value_enum, default_value_t = utils::fastq::MateCheck::Error)]
+    mate_names: utils::fastq::MateCheck,
+
+    /// The number of worker threads used for barcode extraction. If 0, one thread is used per
+    /// available CPU core.
+    #[arg(short('j'), long, default_value_t = 1)]
+    threads: usize,
+
+    /// If set, output rows are written in whatever order worker threads finish them, rather than in
+    /// input order. Has no effect with a single thread.
+    #[arg(long)]
+    unordered: bool,
+}
+
+/// Commands run instead of barcode extraction
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Report the number of unique barcodes, their count distribution and Gini coefficient, and a
+    /// saturation curve from subsampling, for a table of barcodes extracted or counted by bcbuddy
+    Complexity(complexity::ComplexityArguments),
+}
+
+/// The result of running the extractors over one set of mate reads
+enum ReadOutcome {
+    /// Every extractor matched. Holds the read identifiers and the captured sequences of all
+    /// extractors, in output column order.
+    Matched {
+        identifiers: Vec<String>,
+        captures: Vec<String>,
+        /// Each capture group as it was sequenced, before quality masking, transforms and whitelist
+        /// correction
+        sequenced_captures: Vec<String>,
+        edits: Vec<usize>,
+        strands: Vec<barcodes::Strand>,
+        /// The sum of the Phred scores of the bases in each capture group, if the read has quality
+        /// scores
+        quality_sums: Vec<Option<u64>>,
+        /// The mean Phred score of the bases in each capture group, if the read has quality scores
+        /// and the group is not empty
+        mean_qualities: Vec<Option<f64>>,
+        /// For each capture group with a whitelist, its barcode before correction and how it
+        /// compared to the whitelist
+        corrections: Vec<Option<(String, barcodes::whitelist::Correction)>>,
+        /// The sample the read was assigned to, if demultiplexing and it matched one
+        sample: Option<usize>,
+    },
+    /// At least one extractor failed to match. Holds the reads so they can be written out, and
+    /// the strand each extractor matched on, if any.
+    Unmatched {
+        records: Vec<utils::fastq::FASTQRecord>,
+        strands: Vec<Option<barcodes::Strand>>,
+    },
+    /// Every extractor matched, but a capture group failed the quality requirements.
+    FailedQuality {
+        strands: Vec<barcodes::Strand>,
+    },
+    /// Every extractor matched, but a capture group's transforms could not be applied (e.g. it
+    /// could not be reverse-complemented). Holds the reads so they can be written out with the
+    /// unmatched reads.
+    FailedTransform {
+        records: Vec<utils::fastq::FASTQRecord>,
+        strands: Vec<barcodes::Strand>,
+    },
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let arguments = Arguments::parse();
-    
-    if arguments.source.len() != arguments.regex.len() {
-        Arguments::command().error(clap::error::ErrorKind::WrongNumberOfValues, format!("The number of regexes ({}) must match the number of sources ({})", arguments.regex.len(), arguments.source.len())).exit();
+    let start_time = time::Instant::now();
+    let matches = Arguments::command().get_matches();
+    let mut arguments = Arguments::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+
+    if let Some(command) = arguments.command.take() {
+        return match command {
+            Command::Complexity(complexity_arguments) => complexity::run(complexity_arguments),
+        };
     }
 
-    let source_files: Vec<fs::File> = match arguments.source.into_iter().map(fs::File::open).collect() {
-        Ok(source_files) => source_files,
+    // The files given with each `--source`, which together make up one source
+    let mut source_files = arguments.source.drain(..);
+    let mut sources: Vec<Vec<std::path::PathBuf>> = matches.get_occurrences::<std::path::PathBuf>("source").into_iter().flatten().map(|occurrence| {
+        source_files.by_ref().take(occurrence.count()).collect()
+    }).collect();
+    drop(source_files);
+
+    if let Some(ref config_path) = arguments.config {
+        let config = match config::RunConfig::read(config_path) {
+            Ok(config) => config,
+            Err(error) => { return Err(Box::new(error)); }
+        };
+        let mut config_transforms = Vec::new();
+        for read in config.reads.iter() {
+            sources.push(read.sources().to_vec());
+            // Already checked when the configuration was read
+            arguments.regex.push(read.regex()?);
+            config_transforms.extend(read.transforms()?);
+            arguments.unmatched_reads.extend(read.unmatched_reads.clone());
+        }
+        config_transforms.append(&mut arguments.transform);
+        arguments.transform = config_transforms;
+        arguments.output = arguments.output.or(config.output);
+        arguments.output_compression = arguments.output_compression.or(config.output_compression);
+        arguments.output_format = arguments.output_format.or(config.output_format);
+        arguments.run_stats = arguments.run_stats.or(config.run_stats);
+        arguments.reverse_complement_output |= config.reverse_complement_output;
+        arguments.both_strands |= config.both_strands;
+        arguments.interleaved |= config.interleaved;
+    }
+    let output_path = match arguments.output {
+        Some(ref output_path) => output_path.clone(),
+        None => {
+            Arguments::command().error(clap::error::ErrorKind::MissingRequiredArgument, "An output path must be given with `--output` or in the configuration file").exit();
+        }
+    };
+
+    // An interleaved source stands in for one source per mate, so it is matched by every pattern
+    if arguments.interleaved {
+        sources.dedup();
+        if sources.len() != 1 {
+            Arguments::command().error(clap::error::ErrorKind::ArgumentConflict, format!("With `--interleaved`, all mates must be read from a single source, but {} were given", sources.len())).exit();
+        }
+        let num_mates = if arguments.positions.is_empty() { arguments.regex.len() } else { arguments.positions.len() };
+        sources = vec![sources[0].clone(); num_mates];
+    }
+
+    // Each source is matched by either a regex or a set of positions
+    let patterns = if arguments.positions.is_empty() { &arguments.regex } else { &arguments.positions };
+    if sources.len() != patterns.len() {
+        Arguments::command().error(clap::error::ErrorKind::WrongNumberOfValues, format!("The number of regexes or positions ({}) must match the number of sources ({})", patterns.len(), sources.len())).exit();
+    }
+
+    if !arguments.unmatched_reads.is_empty() && sources.len() != arguments.unmatched_reads.len() {
+        Arguments::command().error(clap::error::ErrorKind::WrongNumberOfValues, format!("The number of unmatched read outputs ({}) must match the number of sources ({})", arguments.unmatched_reads.len(), sources.len())).exit();
+    }
+
+    let opened_sources = if arguments.interleaved { &sources[..1] } else { &sources[..] };
+    let (source_formats, source_files): (Vec<utils::reads::ReadFormat>, Vec<utils::reads::RecordReader>) = match opened_sources.iter().map(|lanes| utils::reads::open_all(lanes.iter().map(std::path::PathBuf::as_path))).collect::<Result<Vec<_>, _>>() {
+        Ok(sources) => sources.into_iter().unzip(),
+        Err(error) => { return Err(Box::new(error)); }
+    };
+
+    let extractors: Vec<Box<dyn barcodes::Extractor>> = match patterns.iter().map(|pattern| -> Result<Box<dyn barcodes::Extractor>, barcodes::BarcodeError> {
+        if !arguments.positions.is_empty() {
+            return Ok(Box::new(barcodes::PositionalExtractor::new(pattern)?));
+        }
+        let extractor = barcodes::BarcodeExtractor::new(pattern)?;
+        if arguments.max_anchor_edits > 0 {
+            Ok(Box::new(extractor.approximate(arguments.max_anchor_edits, arguments.allow_indels)?))
+        } else {
+            Ok(Box::new(extractor))
+        }
+    }).collect() {
+        Ok(extractors) => extractors,
         Err(error) => { return Err(Box::new(error)); }
     };
 
-    let mut out = fs::File::create(arguments.output)?;
+    let capture_group_names: Vec<&String> = extractors.iter().flat_map(|extractor| extractor.capture_group_names()).collect();
 
-    let mut stats_out = arguments.run_stats.map(|path| fs::File::create(path));
+    if let Some(duplicate_name) = capture_group_names.iter().duplicates().next() {
+        Arguments::command().error(clap::error::ErrorKind::InvalidValue, format!("Capture group name \"{}\" is used more than once; names must be unique across all regexes", duplicate_name)).exit();
+    }
+
+    // With `--count`, the UMI capture group is counted separately rather than written as a column
+    let umi_position = capture_group_names.iter().position(|name| name.as_str() == umi::UMI_GROUP_NAME).filter(|_| arguments.count);
+
+    let stats_out = arguments.run_stats.map(fs::File::create);
     if let Some(Err(error)) = stats_out {
         return Err(Box::new(error));
     }
 
-    /*
-    let mut unmatched_outs = arguments.unmatched_reads.map(|path| fs::File::create(path));
-    if let Some(Err(error)) = unmatched_out {
+    let diagnostics_out = arguments.failure_diagnostics.as_ref().map(|path| fs::File::create(path).map(std::io::BufWriter::new));
+    if let Some(Err(error)) = diagnostics_out {
         return Err(Box::new(error));
     }
-    */
+    let mut diagnostics = match diagnostics_out.as_ref().map(|_| barcodes::diagnostics::FailureDiagnostics::new(patterns.iter().map(String::as_str), arguments.diagnostics_sample, arguments.both_strands)).transpose() {
+        Ok(diagnostics) => diagnostics,
+        Err(error) => { return Err(Box::new(error)); }
+    };
 
-    let extractors: Vec<barcodes::BarcodeExtractor> = match arguments.regex.iter().map(String::as_str).map(barcodes::BarcodeExtractor::new).collect() {
-        Ok(extractors) => extractors,
+    let mut unmatched_outs: Vec<utils::compression::Writer> = match arguments.unmatched_reads.iter().map(|path| utils::compression::Writer::create(path, None)).collect() {
+        Ok(unmatched_outs) => unmatched_outs,
+        Err(error) => { return Err(Box::new(error)); }
+    };
+
+    let sample_sheet = match arguments.sample_sheet.as_ref().map(|path| demultiplex::SampleSheet::read(path, &capture_group_names, arguments.index_mismatches)).transpose() {
+        Ok(sample_sheet) => sample_sheet,
         Err(error) => { return Err(Box::new(error)); }
     };
+    let raw_output_path = output_path.to_string_lossy();
+    let per_sample_outputs = sample_sheet.is_some() && raw_output_path.contains("{sample}");
+    let output_paths: Vec<std::path::PathBuf> = match sample_sheet {
+        Some(ref sample_sheet) if per_sample_outputs => {
+            sample_sheet.sample_names().chain(std::iter::once(demultiplex::UNDETERMINED)).map(|name| raw_output_path.replace("{sample}", name).into()).collect()
+        },
+        _ => vec![output_path.clone()],
+    };
+    use utils::table::ColumnType;
+    let mut columns: Vec<(String, ColumnType)> = Vec::new();
+    if sample_sheet.is_some() && !per_sample_outputs {
+        columns.push(("sample".to_string(), ColumnType::Text));
+    }
 
     if arguments.output_read_ids {
-        write!(out, "{}\t", (1..=source_files.len()).map(|i| format!("read{}_id", i)).join("\t"))?;
+        columns.extend((1..=extractors.len()).map(|i| (format!("read{}_id", i), ColumnType::Text)));
     }
 
-    writeln!(out, "{}", extractors.iter().map(|extractor| {
-        extractor.capture_group_names().into_iter().join("\t")
-    }).join("\t"))?;
+    let is_whitelisted = |name: &str| arguments.whitelist.iter().any(|(group, _)| group == name);
+    for (position, name) in capture_group_names.iter().enumerate() {
+        if Some(position) == umi_position {
+            continue;
+        }
+        columns.push((name.to_string(), ColumnType::Text));
+        if is_whitelisted(name) && !arguments.count {
+            columns.push((format!("uncorrected_{}", name), ColumnType::Text));
+            columns.push((format!("{}_distance", name), ColumnType::Text));
+        }
+        if arguments.output_quality {
+            columns.push((format!("{}_mean_quality", name), ColumnType::Float));
+        }
+    }
 
-    let mut reads_iterators: Vec<utils::fastq::FASTQReader<_>> = source_files.into_iter().map(utils::fastq::FASTQReader::read_fastq).collect();
+    if arguments.count {
+        columns.push(("read_count".to_string(), ColumnType::Integer));
+        if umi_position.is_some() {
+            columns.push(("umi_count".to_string(), ColumnType::Integer));
+        }
+    } else if arguments.max_anchor_edits > 0 {
+        columns.extend((1..=extractors.len()).map(|i| (format!("read{}_edits", i), ColumnType::Integer)));
+    }
+    if arguments.both_strands && !arguments.count {
+        columns.extend((1..=extractors.len()).map(|i| (format!("read{}_strand", i), ColumnType::Text)));
+    }
 
-    let mut num_reads: usize = 0;
-    let mut num_matching_reads: usize = 0;
-    loop {
-        let all_records: Vec<utils::fastq::FASTQRecord> = match reads_iterators.iter_mut().map(|i| i.next()).collect() {
-            Some(Ok(records)) => records,
-            Some(Err(error)) => { return Err(Box::new(error)); },
-            None => {
-                break;
-            }
-        };
-        num_reads += 1;
+    let mut outs: Vec<utils::table::TableWriter> = match output_paths.iter().map(|path| utils::table::TableWriter::create(path, &columns, arguments.output_format, arguments.output_compression, arguments.row_group_size as usize)).collect() {
+        Ok(outs) => outs,
+        Err(error) => { return Err(Box::new(error)); }
+    };
+
+    let mut counters: Vec<counting::BarcodeCounter> = outs.iter().map(|_| {
+        counting::BarcodeCounter::new(arguments.max_barcodes_in_memory, arguments.temp_dir.clone().unwrap_or_else(std::env::temp_dir))
+    }).collect();
 
-        let all_captures: Vec<Vec<&str>> = match all_records.iter().zip(extractors.iter()).map(|(record, extractor)| { extractor.extract(&record.sequence) }).collect() {
-            Some(captures) => captures,
+    let mut read_tuples = if arguments.interleaved {
+        utils::fastq::FASTQTupleReader::read_interleaved(source_files.into_iter().next().expect("an interleaved source is opened"), extractors.len(), arguments.mate_names)
+    } else {
+        utils::fastq::FASTQTupleReader::read_fastqs(source_files, arguments.mate_names)
+    };
+
+    let threads = match arguments.threads {
+        0 => std::thread::available_parallelism().map_or(1, usize::from),
+        threads => threads,
+    };
+
+    let quality_filter = quality::QualityFilter {
+        min_base_quality: arguments.min_base_quality,
+        max_expected_errors: arguments.max_expected_errors,
+        low_quality_action: arguments.low_quality_action,
+    };
+    // Each source's files, as named in messages and run statistics
+    let source_names: Vec<String> = sources.iter().map(|lanes| lanes.iter().map(|path| path.display()).join(",")).collect();
+    if let Some((source, format)) = source_names.iter().zip(source_formats.iter()).find(|(_, format)| !format.has_quality_scores()).filter(|_| quality_filter.is_enabled()) {
+        Arguments::command().error(clap::error::ErrorKind::ArgumentConflict, format!("Quality filtering requires quality scores, but source \"{}\" is {}", source, format)).exit();
+    }
+
+    // The transforms for each capture group, in output column order
+    if let Some((unknown_group, _)) = arguments.transform.iter().find(|(group, _)| !capture_group_names.contains(&group)) {
+        Arguments::command().error(clap::error::ErrorKind::InvalidValue, format!("Transform given for unknown capture group \"{}\"", unknown_group)).exit();
+    }
+    let group_transforms: Vec<Vec<barcodes::transform::Transform>> = capture_group_names.iter().map(|name| {
+        let mut transforms: Vec<barcodes::transform::Transform> = arguments.transform.iter().filter(|(group, _)| group == *name).flat_map(|(_, transforms)| transforms.iter().cloned()).collect();
+        if arguments.reverse_complement_output {
+            transforms.push(barcodes::transform::Transform::ReverseComplement);
+        }
+        transforms
+    }).collect();
+
+    // The whitelist for each capture group, in output column order
+    if let Some((unknown_group, _)) = arguments.whitelist.iter().find(|(group, _)| !capture_group_names.contains(&group)) {
+        Arguments::command().error(clap::error::ErrorKind::InvalidValue, format!("Whitelist given for unknown capture group \"{}\"", unknown_group)).exit();
+    }
+    if let Some(duplicate_group) = arguments.whitelist.iter().map(|(group, _)| group).duplicates().next() {
+        Arguments::command().error(clap::error::ErrorKind::InvalidValue, format!("More than one whitelist given for capture group \"{}\"", duplicate_group)).exit();
+    }
+    let group_whitelists: Vec<Option<barcodes::whitelist::Whitelist>> = match capture_group_names.iter().map(|name| {
+        arguments.whitelist.iter().find(|(group, _)| group == *name).map(|(_, path)| barcodes::whitelist::Whitelist::read(path, name, arguments.whitelist_distance)).transpose()
+    }).collect() {
+        Ok(group_whitelists) => group_whitelists,
+        Err(error) => { return Err(Box::new(error)); }
+    };
+
+    let process = |records: Vec<utils::fastq::FASTQRecord>| -> ReadOutcome {
+        let forward_extractions: Vec<Option<barcodes::Extraction>> = records.iter().zip(extractors.iter()).map(|(record, extractor)| { extractor.extract(&record.sequence) }).collect();
+        // Reads that cannot be reverse-complemented (e.g. with IUPAC bases) are only searched forward
+        let reverse_sequences: Vec<Option<String>> = forward_extractions.iter().zip(records.iter()).map(|(extraction, record)| {
+            if arguments.both_strands && extraction.is_none() {
+                utils::reverse_complement(&record.sequence).ok()
+            } else {
+                None
+            }
+        }).collect();
+        let extractions: Vec<Option<(barcodes::Extraction, barcodes::Strand)>> = forward_extractions.into_iter().zip(reverse_sequences.iter()).zip(extractors.iter()).map(|((extraction, reverse_sequence), extractor)| {
+            match extraction {
+                Some(extraction) => Some((extraction, barcodes::Strand::Forward)),
+                None => reverse_sequence.as_ref().and_then(|reverse_sequence| extractor.extract(reverse_sequence)).map(|extraction| (extraction, barcodes::Strand::Reverse)),
+            }
+        }).collect();
+        let (captures, sequenced_captures, edits, strands, quality_sums, mean_qualities) = match extractions.iter().map(Option::as_ref).collect::<Option<Vec<&(barcodes::Extraction, barcodes::Strand)>>>() {
+            Some(extractions) => {
+                let strands: Vec<barcodes::Strand> = extractions.iter().map(|(_, strand)| *strand).collect();
+                let edits: Vec<usize> = extractions.iter().map(|(extraction, _)| extraction.edits).collect();
+                let mut captures: Vec<String> = Vec::new();
+                let mut sequenced_captures: Vec<String> = Vec::new();
+                let mut quality_sums: Vec<Option<u64>> = Vec::new();
+                let mut mean_qualities: Vec<Option<f64>> = Vec::new();
+                let mut transforms = group_transforms.iter();
+                let mut transform_failed = false;
+                'reads: for ((extraction, strand), record) in extractions.iter().zip(records.iter()) {
+                    for ((sequence, span), transforms) in extraction.captures().zip(extraction.spans.iter()).zip(transforms.by_ref()) {
+                        let quality_scores = record.quality_scores.as_ref().map(|quality_scores| strand.oriented_quality_scores(quality_scores, span));
+                        let quality_sum: Option<u64> = quality_scores.as_ref().map(|quality_scores| quality::phred_scores(quality_scores).map(u64::from).sum());
+                        sequenced_captures.push(sequence.to_string());
+                        quality_sums.push(quality_sum);
+                        mean_qualities.push(quality_sum.zip(quality_scores.as_ref()).filter(|(_, quality_scores)| !quality_scores.is_empty()).map(|(quality_sum, quality_scores)| quality_sum as f64 / quality_scores.len() as f64));
+                        let sequence = match quality_filter.apply(sequence, quality_scores.as_deref()) {
+                            Some(sequence) => sequence,
+                            None => { return ReadOutcome::FailedQuality { strands }; }
+                        };
+                        match barcodes::transform::apply_all(transforms, &sequence) {
+                            Ok(capture) => { captures.push(capture); },
+                            Err(_) => {
+                                transform_failed = true;
+                                break 'reads;
+                            }
+                        }
+                    }
+                }
+                if transform_failed {
+                    return ReadOutcome::FailedTransform { records, strands };
+                }
+                (captures, sequenced_captures, edits, strands, quality_sums, mean_qualities)
+            },
             None => {
-                //TODO: Write record to unmatched_out
-                continue;
+                let strands = extractions.iter().map(|extraction| extraction.as_ref().map(|(_, strand)| *strand)).collect();
+                return ReadOutcome::Unmatched { records, strands };
             }
         };
-        num_matching_reads += 1;
+        let mut captures = captures;
+        let corrections = captures.iter_mut().zip(group_whitelists.iter()).map(|(capture, whitelist)| {
+            whitelist.as_ref().map(|whitelist| {
+                let correction = whitelist.correct(capture);
+                let uncorrected = match correction {
+                    barcodes::whitelist::Correction::Corrected(ref barcode, _) => std::mem::replace(capture, barcode.clone()),
+                    _ => capture.clone(),
+                };
+                (uncorrected, correction)
+            })
+        }).collect();
+        let sample = sample_sheet.as_ref().and_then(|sample_sheet| sample_sheet.assign(&captures, &records[0].identifier));
+        ReadOutcome::Matched {
+            identifiers: records.into_iter().map(|record| record.identifier).collect(),
+            captures,
+            sequenced_captures,
+            edits,
+            strands,
+            quality_sums,
+            mean_qualities,
+            corrections,
+            sample
+        }
+    };
+
+    let mut stats = stats::RunStats::new(source_names.iter().zip(extractors.iter()).zip(patterns.iter()).map(|((source, extractor), pattern)| {
+        (source.as_str(), pattern.as_str(), extractor.capture_group_names())
+    }));
+    pipeline::run(read_tuples.by_ref(), threads, !arguments.unordered, process, |outcome| {
+        match outcome {
+            ReadOutcome::Matched { identifiers, captures, sequenced_captures, edits, strands, quality_sums, mean_qualities, corrections, sample } => {
+                stats.record_extraction(&strands.iter().copied().map(Some).collect::<Vec<_>>());
+                stats.record_captures(&sequenced_captures, &quality_sums);
+                stats.record_corrections(corrections.iter().map(|correction| correction.as_ref().map(|(_, correction)| correction)));
+
+                let sample_name = sample_sheet.as_ref().map(|sample_sheet| sample.map_or(demultiplex::UNDETERMINED, |sample| sample_sheet.sample_name(sample)));
+                if let Some(sample_name) = sample_name {
+                    stats.record_sample(sample_name);
+                }
+                let output_index = match sample_sheet {
+                    Some(ref sample_sheet) if per_sample_outputs => sample.unwrap_or(sample_sheet.num_samples()),
+                    _ => 0,
+                };
+                let sample_column = sample_name.filter(|_| !per_sample_outputs);
+
+                if arguments.count {
+                    // The UMI, if any, goes last so that reads sharing all other capture groups are
+                    // adjacent once sorted
+                    let mut captures = captures;
+                    if let Some(umi_position) = umi_position {
+                        let umi = captures.remove(umi_position);
+                        captures.push(umi);
+                    }
+                    let key = sample_column.into_iter().map(str::to_string).chain(captures).join("\t");
+                    counters[output_index].add(key)?;
+                    return Ok(());
+                }
 
-        if arguments.output_read_ids {
-            write!(out, "{}\t", all_records.iter().map(|r| &r.identifier).join("\t"))?;
+                use utils::table::Value;
+                let mut row: Vec<Value> = Vec::with_capacity(columns.len());
+                if let Some(sample_name) = sample_column {
+                    row.push(Value::Text(sample_name.into()));
+                }
+                if arguments.output_read_ids {
+                    row.extend(identifiers.iter().map(|identifier| Value::Text(identifier.into())));
+                }
+                for ((capture, correction), mean_quality) in captures.iter().zip(corrections.iter()).zip(mean_qualities.iter()) {
+                    row.push(Value::Text(capture.into()));
+                    if let Some((uncorrected, correction)) = correction {
+                        row.push(Value::Text(uncorrected.into()));
+                        row.push(Value::Text(correction.to_string().into()));
+                    }
+                    if arguments.output_quality {
+                        row.push(mean_quality.map_or(Value::Missing, Value::Float));
+                    }
+                }
+                if arguments.max_anchor_edits > 0 {
+                    row.extend(edits.iter().map(|edits| Value::Integer(*edits as u64)));
+                }
+                if arguments.both_strands {
+                    row.extend(strands.iter().map(|strand| Value::Text(strand.to_string().into())));
+                }
+                outs[output_index].write_row(&row)?;
+            },
+            ReadOutcome::Unmatched { records, strands } => {
+                stats.record_extraction(&strands);
+                if let Some(ref mut diagnostics) = diagnostics {
+                    for (index, (record, _)) in records.iter().zip(strands.iter()).enumerate().filter(|(_, (_, strand))| strand.is_none()) {
+                        diagnostics.record_failure(index, &record.sequence);
+                    }
+                }
+                for (record, unmatched_out) in records.iter().zip(unmatched_outs.iter_mut()) {
+                    write!(unmatched_out, "{}", record)?;
+                }
+            },
+            ReadOutcome::FailedQuality { strands } => {
+                stats.record_extraction(&strands.iter().copied().map(Some).collect::<Vec<_>>());
+                stats.reads_failing_quality += 1;
+            },
+            ReadOutcome::FailedTransform { records, strands } => {
+                stats.record_extraction(&strands.iter().copied().map(Some).collect::<Vec<_>>());
+                stats.reads_failing_transforms += 1;
+                for (record, unmatched_out) in records.iter().zip(unmatched_outs.iter_mut()) {
+                    write!(unmatched_out, "{}", record)?;
+                }
+            }
         }
-        
-        writeln!(out, "{}", all_captures.iter().map(|captures| {
-            if arguments.reverse_complement_output {
-                captures.into_iter().map(|sequence| utils::reverse_complement(sequence)).join("\t")
-            } else {
-                captures.into_iter().join("\t")
+        Ok(())
+    })?;
+
+    for (mut out, counter) in outs.into_iter().zip(counters) {
+        if arguments.count {
+            match umi_position {
+                Some(_) => counter.write_sorted_with_umis(&mut out, arguments.umi_correction)?,
+                None => counter.write_sorted(&mut out)?,
             }
-        }).join("\t"))?;
+        }
+        out.finish()?;
+    }
+    for unmatched_out in unmatched_outs {
+        unmatched_out.finish()?;
     }
 
-    if let Some(Ok(ref mut stats_out)) = stats_out {
-        writeln!(stats_out, "{{")?;
-        writeln!(stats_out, "\t\"total_reads\": {},", num_reads)?;
-        writeln!(stats_out, "\t\"reads_with_BC\": {}", num_matching_reads)?;
-        write!(stats_out, "}}")?;
+    if let Some(Ok(stats_out)) = stats_out {
+        stats.mismatched_mate_names = read_tuples.mismatched_mates();
+        stats.finish(start_time.elapsed());
+        serde_json::to_writer_pretty(stats_out, &stats)?;
+    }
+    if let (Some(Ok(diagnostics_out)), Some(diagnostics)) = (diagnostics_out, diagnostics) {
+        diagnostics.write(diagnostics_out)?;
     }
 
     Ok(())
@@ -0,0 +1,82 @@
+use std::{collections::BTreeMap, io, thread};
+
+/// The number of input items handed to a worker thread at a time
+const BATCH_SIZE: usize = 4096;
+
+/// Run `process` over every item from `source`, passing each result to `consume` on the calling
+/// thread.
+///
+/// With a single thread, items are processed inline. Otherwise, one thread reads batches of items,
+/// `threads` worker threads process them, and the calling thread consumes the results. Results are
+/// consumed in input order when `ordered` is set, or as soon as each batch finishes otherwise. At
+/// most `2 * threads` batches are read but not yet consumed at any time, so that a slow batch holds
+/// back only a bounded number of finished ones.
+pub fn run<S, T, U, P, C>(source: S, threads: usize, ordered: bool, process: P, mut consume: C) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: Iterator<Item = Result<T, io::Error>> + Send,
+    T: Send,
+    U: Send,
+    P: Fn(T) -> U + Sync,
+    C: FnMut(U) -> Result<(), Box<dyn std::error::Error>>,
+{
+    if threads <= 1 {
+        for item in source {
+            consume(process(item?))?;
+        }
+        return Ok(());
+    }
+
+    thread::scope(|scope| {
+        let (batch_sender, batch_receiver) = crossbeam_channel::bounded::<(usize, Vec<T>)>(threads * 2);
+        let (result_sender, result_receiver) = crossbeam_channel::bounded::<(usize, Vec<U>)>(threads * 2);
+        // The reader takes a slot before sending each batch, and the slot is freed once the batch is
+        // consumed
+        let (slot_sender, slot_receiver) = crossbeam_channel::bounded::<()>(threads * 2);
+
+        let reader = scope.spawn(move || -> Result<(), io::Error> {
+            let mut source = source;
+            for index in 0.. {
+                let batch: Vec<T> = source.by_ref().take(BATCH_SIZE).collect::<Result<_, _>>()?;
+                if batch.is_empty() || slot_sender.send(()).is_err() || batch_sender.send((index, batch)).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        });
+
+        for _ in 0..threads {
+            let batch_receiver = batch_receiver.clone();
+            let result_sender = result_sender.clone();
+            let process = &process;
+            scope.spawn(move || {
+                for (index, batch) in batch_receiver {
+                    if result_sender.send((index, batch.into_iter().map(process).collect())).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(batch_receiver);
+        drop(result_sender);
+
+        // Batches may finish out of order; hold on to early ones until their turn comes
+        let mut pending: BTreeMap<usize, Vec<U>> = BTreeMap::new();
+        let mut next_index: usize = 0;
+        for (index, results) in result_receiver {
+            if !ordered {
+                results.into_iter().try_for_each(&mut consume)?;
+                let _ = slot_receiver.recv();
+                continue;
+            }
+            pending.insert(index, results);
+            while let Some(results) = pending.remove(&next_index) {
+                results.into_iter().try_for_each(&mut consume)?;
+                let _ = slot_receiver.recv();
+                next_index += 1;
+            }
+        }
+
+        reader.join().expect("reader thread panicked")?;
+        Ok(())
+    })
+}
@@ -0,0 +1,105 @@
+use std::borrow::Cow;
+
+/// The offset of Phred scores in FASTQ quality strings (Sanger/Illumina 1.8+ encoding)
+pub const PHRED_OFFSET: u8 = 33;
+
+/// Decode Phred scores from a FASTQ quality string
+pub fn phred_scores(quality_scores: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    quality_scores.iter().map(|score| score.saturating_sub(PHRED_OFFSET))
+}
+
+/// What to do with bases that fall below the minimum base quality
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LowQualityAction {
+    /// Discard the whole read
+    Discard,
+    /// Replace the low-quality bases with `N`
+    Mask,
+}
+
+/// Quality requirements for the bases of extracted capture groups
+pub struct QualityFilter {
+    pub min_base_quality: Option<u8>,
+    pub max_expected_errors: Option<f64>,
+    pub low_quality_action: LowQualityAction,
+}
+
+impl QualityFilter {
+    pub fn is_enabled(&self) -> bool {
+        self.min_base_quality.is_some() || self.max_expected_errors.is_some()
+    }
+
+    /// Check a captured sequence against its (equal-length, Phred+33 encoded) quality scores.
+    /// Returns `None` if the capture should cause the read to be discarded, or the capture with any
+    /// low-quality bases masked. Captures from records without quality scores (e.g. SAM's `*`)
+    /// cannot be shown to pass, so they are discarded whenever the filter is enabled.
+    pub fn apply<'a>(&self, sequence: &'a str, quality_scores: Option<&[u8]>) -> Option<Cow<'a, str>> {
+        if !self.is_enabled() {
+            return Some(Cow::Borrowed(sequence));
+        }
+        let quality_scores = quality_scores?;
+        let phred_scores = || phred_scores(quality_scores);
+
+        if let Some(max_expected_errors) = self.max_expected_errors {
+            let expected_errors: f64 = phred_scores().map(|phred| 10f64.powf(-f64::from(phred) / 10.0)).sum();
+            if expected_errors > max_expected_errors {
+                return None;
+            }
+        }
+
+        let min_base_quality = match self.min_base_quality {
+            Some(min_base_quality) => min_base_quality,
+            None => { return Some(Cow::Borrowed(sequence)); }
+        };
+        if phred_scores().all(|phred| phred >= min_base_quality) {
+            return Some(Cow::Borrowed(sequence));
+        }
+
+        match self.low_quality_action {
+            LowQualityAction::Discard => None,
+            LowQualityAction::Mask => {
+                Some(Cow::Owned(sequence.chars().zip(phred_scores()).map(|(base, phred)| {
+                    if phred >= min_base_quality { base } else { 'N' }
+                }).collect()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(min_base_quality: Option<u8>, max_expected_errors: Option<f64>, low_quality_action: LowQualityAction) -> QualityFilter {
+        QualityFilter { min_base_quality, max_expected_errors, low_quality_action }
+    }
+
+    #[test]
+    fn bases_at_the_minimum_quality_pass() {
+        // "?" is Phred 30 and ">" is Phred 29
+        let discard = filter(Some(30), None, LowQualityAction::Discard);
+        assert_eq!(discard.apply("ACGT", Some(b"????")).as_deref(), Some("ACGT"));
+        assert_eq!(discard.apply("ACGT", Some(b"??>?")), None);
+        let mask = filter(Some(30), None, LowQualityAction::Mask);
+        assert_eq!(mask.apply("ACGT", Some(b"????")).as_deref(), Some("ACGT"));
+        assert_eq!(mask.apply("ACGT", Some(b">??>")).as_deref(), Some("NCGN"));
+    }
+
+    #[test]
+    fn expected_errors_at_the_maximum_pass() {
+        // "+" is Phred 10, an error probability of 0.1
+        let filter = filter(None, Some(0.2), LowQualityAction::Discard);
+        assert_eq!(filter.apply("AC", Some(b"++")).as_deref(), Some("AC"));
+        assert_eq!(filter.apply("ACG", Some(b"+++")), None);
+        // Even high-quality bases add a little to the expected errors
+        assert_eq!(filter.apply("ACG", Some(b"++I")), None);
+    }
+
+    #[test]
+    fn records_without_quality_scores_only_pass_without_a_filter() {
+        assert_eq!(filter(None, None, LowQualityAction::Discard).apply("ACGT", None).as_deref(), Some("ACGT"));
+        assert_eq!(filter(Some(0), None, LowQualityAction::Discard).apply("ACGT", None), None);
+        assert_eq!(filter(Some(0), None, LowQualityAction::Mask).apply("ACGT", None), None);
+        assert_eq!(filter(None, Some(1.0), LowQualityAction::Discard).apply("ACGT", None), None);
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::barcodes::{Strand, whitelist::Correction};
+
+/// Statistics describing a complete bcbuddy run, written as JSON with `--run-stats`
+#[derive(Serialize, Debug)]
+pub struct RunStats {
+    pub total_reads: usize,
+    /// Reads for which every extractor matched and every capture group passed quality filtering
+    #[serde(rename = "reads_with_BC")]
+    pub reads_with_barcodes: usize,
+    /// Reads with a capture group that failed quality filtering, or without quality scores when
+    /// filtering (e.g. SAM records with `QUAL *`)
+    pub reads_failing_quality: usize,
+    /// Reads with a capture group that could not be transformed (e.g. reverse-complemented)
+    pub reads_failing_transforms: usize,
+    /// Sets of mate reads whose names did not match (only counted with `--mate-names warn`)
+    pub mismatched_mate_names: usize,
+    pub wall_time_seconds: f64,
+    pub sources: Vec<SourceStats>,
+    /// The number of unmatched reads for each combination of failing extractors, keyed by e.g.
+    /// "read1" or "read1+read2"
+    pub failed_extractors: BTreeMap<String, usize>,
+    pub capture_groups: Vec<CaptureGroupStats>,
+    /// The number of reads assigned to each sample (only when demultiplexing)
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub samples: BTreeMap<String, usize>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SourceStats {
+    pub source: String,
+    /// The regex (or capture group positions) used to extract barcodes from the source
+    pub regex: String,
+    pub reads_matched: usize,
+    /// Reads matched on the reverse complement strand (only with `--both-strands`)
+    pub reads_matched_reverse: usize,
+    pub match_rate: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CaptureGroupStats {
+    pub name: String,
+    /// The (1-based) source the group is extracted from
+    pub source: usize,
+    /// The number of reads with each capture length, as sequenced
+    pub length_histogram: BTreeMap<usize, usize>,
+    pub n_bases: usize,
+    pub reads_with_n: usize,
+    pub mean_quality: Option<f64>,
+    /// How the group's barcodes compared to its whitelist (only with `--whitelist`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub whitelist: Option<WhitelistStats>,
+    #[serde(skip)]
+    quality_sum: u64,
+    #[serde(skip)]
+    num_bases: u64,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct WhitelistStats {
+    pub exact: usize,
+    pub corrected: usize,
+    pub ambiguous: usize,
+    pub unmatched: usize,
+    /// The fraction of barcodes that were corrected to a whitelist entry
+    pub correction_rate: f64,
+}
+
+impl RunStats {
+    pub fn new<'a>(sources: impl IntoIterator<Item = (&'a str, &'a str, &'a [String])>) -> Self {
+        let mut stats = Self {
+            total_reads: 0,
+            reads_with_barcodes: 0,
+            reads_failing_quality: 0,
+            reads_failing_transforms: 0,
+            mismatched_mate_names: 0,
+            wall_time_seconds: 0.0,
+            sources: Vec::new(),
+            failed_extractors: BTreeMap::new(),
+            capture_groups: Vec::new(),
+            samples: BTreeMap::new(),
+        };
+        for (index, (source, regex, capture_group_names)) in sources.into_iter().enumerate() {
+            stats.sources.push(SourceStats {
+                source: source.to_string(),
+                regex: regex.to_string(),
+                reads_matched: 0,
+                reads_matched_reverse: 0,
+                match_rate: 0.0,
+            });
+            stats.capture_groups.extend(capture_group_names.iter().map(|name| CaptureGroupStats {
+                name: name.clone(),
+                source: index + 1,
+                length_histogram: BTreeMap::new(),
+                n_bases: 0,
+                reads_with_n: 0,
+                mean_quality: None,
+                whitelist: None,
+                quality_sum: 0,
+                num_bases: 0,
+            }));
+        }
+        stats
+    }
+
+    /// Record the result of each extractor on a set of mate reads: the strand it matched on, or
+    /// `None` if it did not match
+    pub fn record_extraction(&mut self, strands: &[Option<Strand>]) {
+        self.total_reads += 1;
+        for (source, strand) in self.sources.iter_mut().zip(strands.iter()) {
+            match strand {
+                Some(Strand::Forward) => { source.reads_matched += 1; },
+                Some(Strand::Reverse) => {
+                    source.reads_matched += 1;
+                    source.reads_matched_reverse += 1;
+                },
+                None => {},
+            }
+        }
+        if strands.iter().any(Option::is_none) {
+            let key = strands.iter().enumerate().filter(|(_, strand)| strand.is_none()).map(|(index, _)| format!("read{}", index + 1)).collect::<Vec<_>>().join("+");
+            *self.failed_extractors.entry(key).or_insert(0) += 1;
+        }
+    }
+
+    /// Record the capture groups of a read that was written to the output, as they were sequenced
+    /// (before any masking, transforms or correction), along with the sum of the Phred scores of
+    /// each group, if the read has quality scores
+    pub fn record_captures(&mut self, captures: &[String], quality_sums: &[Option<u64>]) {
+        self.reads_with_barcodes += 1;
+        for ((group, capture), quality_sum) in self.capture_groups.iter_mut().zip(captures.iter()).zip(quality_sums.iter()) {
+            *group.length_histogram.entry(capture.len()).or_insert(0) += 1;
+            let n_bases = capture.bytes().filter(|base| base.eq_ignore_ascii_case(&b'N')).count();
+            group.n_bases += n_bases;
+            if n_bases > 0 {
+                group.reads_with_n += 1;
+            }
+            if let Some(quality_sum) = quality_sum {
+                group.quality_sum += quality_sum;
+                group.num_bases += capture.len() as u64;
+            }
+        }
+    }
+
+    /// Record how each capture group of a read that was written to the output compared to its
+    /// whitelist, if it has one
+    pub fn record_corrections<'a>(&mut self, corrections: impl IntoIterator<Item = Option<&'a Correction>>) {
+        for (group, correction) in self.capture_groups.iter_mut().zip(corrections) {
+            let correction = match correction {
+                Some(correction) => correction,
+                None => { continue; }
+            };
+            let whitelist = group.whitelist.get_or_insert_with(WhitelistStats::default);
+            match correction {
+                Correction::Exact => { whitelist.exact += 1; },
+                Correction::Corrected(..) => { whitelist.corrected += 1; },
+                Correction::Ambiguous => { whitelist.ambiguous += 1; },
+                Correction::Unmatched => { whitelist.unmatched += 1; },
+            }
+        }
+    }
+
+    pub fn record_sample(&mut self, sample_name: &str) {
+        match self.samples.get_mut(sample_name) {
+            Some(count) => { *count += 1; },
+            None => { self.samples.insert(sample_name.to_string(), 1); }
+        }
+    }
+
+    pub fn finish(&mut self, wall_time: std::time::Duration) {
+        self.wall_time_seconds = wall_time.as_secs_f64();
+        for source in self.sources.iter_mut() {
+            source.match_rate = if self.total_reads > 0 { source.reads_matched as f64 / self.total_reads as f64 } else { 0.0 };
+        }
+        for group in self.capture_groups.iter_mut() {
+            group.mean_quality = (group.num_bases > 0).then(|| group.quality_sum as f64 / group.num_bases as f64);
+            if let Some(ref mut whitelist) = group.whitelist {
+                let total = whitelist.exact + whitelist.corrected + whitelist.ambiguous + whitelist.unmatched;
+                whitelist.correction_rate = if total > 0 { whitelist.corrected as f64 / total as f64 } else { 0.0 };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_keys_are_written_alongside_the_breakdowns() {
+        let capture_group_names = [vec!["BC1".to_string()], vec!["BC2".to_string()]];
+        let mut stats = RunStats::new([("R1.fastq", "ACGT(?P<BC1>[ACGT]{4})", capture_group_names[0].as_slice()), ("R2.fastq", "TTGG(?P<BC2>[ACGT]{2})", capture_group_names[1].as_slice())]);
+        stats.record_extraction(&[Some(Strand::Forward), Some(Strand::Reverse)]);
+        stats.record_captures(&["ACNT".to_string(), "GG".to_string()], &[Some(120), None]);
+        stats.record_extraction(&[Some(Strand::Forward), None]);
+        stats.finish(std::time::Duration::from_millis(1500));
+
+        let json = serde_json::to_value(&stats).expect("failed to serialise stats");
+        assert_eq!(json["total_reads"], 2);
+        assert_eq!(json["reads_with_BC"], 1);
+        assert_eq!(json["wall_time_seconds"], 1.5);
+        assert_eq!(json["failed_extractors"], serde_json::json!({ "read2": 1 }));
+        assert_eq!(json["sources"][1], serde_json::json!({ "source": "R2.fastq", "regex": "TTGG(?P<BC2>[ACGT]{2})", "reads_matched": 1, "reads_matched_reverse": 1, "match_rate": 0.5 }));
+        assert_eq!(json["capture_groups"][0], serde_json::json!({ "name": "BC1", "source": 1, "length_histogram": { "4": 1 }, "n_bases": 1, "reads_with_n": 1, "mean_quality": 30.0 }));
+        assert_eq!(json["capture_groups"][1]["mean_quality"], serde_json::Value::Null);
+        // Empty sections are left out, and the renamed count only appears under its legacy key
+        assert!(json.get("samples").is_none());
+        assert!(json.get("reads_with_barcodes").is_none());
+    }
+}
@@ -0,0 +1,87 @@
+/// The capture group name reserved for unique molecular identifiers
+pub const UMI_GROUP_NAME: &str = "UMI";
+
+/// How UMIs that may be sequencing errors of one another are merged before counting molecules
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UmiCorrection {
+    /// Every distinct UMI is a separate molecule
+    None,
+    /// Merge UMIs using the directional network method of UMI-tools: a UMI is absorbed by one a
+    /// single mismatch away that has at least twice (minus one) as many reads
+    Directional,
+}
+
+/// Count the distinct molecules among the UMIs (and their read counts) seen for one combination of
+/// barcodes
+pub fn count_molecules(umis: &[(String, usize)], correction: UmiCorrection) -> usize {
+    if correction == UmiCorrection::None {
+        return umis.len();
+    }
+
+    let mut order: Vec<usize> = (0..umis.len()).collect();
+    order.sort_by(|&a, &b| umis[b].1.cmp(&umis[a].1).then_with(|| umis[a].0.cmp(&umis[b].0)));
+
+    let mut visited = vec![false; umis.len()];
+    let mut molecules = 0;
+    for &root in order.iter() {
+        if visited[root] {
+            continue;
+        }
+        molecules += 1;
+        visited[root] = true;
+        let mut pending = vec![root];
+        while let Some(parent) = pending.pop() {
+            let (parent_umi, parent_count) = &umis[parent];
+            for child in 0..umis.len() {
+                let (child_umi, child_count) = &umis[child];
+                if !visited[child] && *parent_count + 1 >= 2 * child_count && is_single_mismatch(parent_umi, child_umi) {
+                    visited[child] = true;
+                    pending.push(child);
+                }
+            }
+        }
+    }
+    molecules
+}
+
+fn is_single_mismatch(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).filter(|(a, b)| a != b).count() == 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(umis: &[(&str, usize)], correction: UmiCorrection) -> usize {
+        count_molecules(&umis.iter().map(|(umi, count)| (umi.to_string(), *count)).collect::<Vec<_>>(), correction)
+    }
+
+    #[test]
+    fn without_correction_every_umi_is_a_molecule() {
+        assert_eq!(count(&[("AAAA", 10), ("AAAT", 1), ("GGGG", 3)], UmiCorrection::None), 3);
+    }
+
+    #[test]
+    fn directional_merging_needs_twice_as_many_reads_minus_one() {
+        // 10 + 1 >= 2 * 5
+        assert_eq!(count(&[("AAAA", 10), ("AAAT", 5)], UmiCorrection::Directional), 1);
+        // 10 + 1 < 2 * 6
+        assert_eq!(count(&[("AAAA", 10), ("AAAT", 6)], UmiCorrection::Directional), 2);
+        // Equal single-read UMIs are merged (1 + 1 >= 2 * 1)
+        assert_eq!(count(&[("AAAT", 1), ("AAAA", 1)], UmiCorrection::Directional), 1);
+    }
+
+    #[test]
+    fn directional_merging_needs_a_single_mismatch() {
+        assert_eq!(count(&[("AAAA", 10), ("AATT", 1)], UmiCorrection::Directional), 2);
+        assert_eq!(count(&[("AAAA", 10), ("AAA", 1)], UmiCorrection::Directional), 2);
+    }
+
+    #[test]
+    fn directional_merging_follows_chains() {
+        // "AATT" is two mismatches from "AAAA", but is absorbed through "AAAT"
+        assert_eq!(count(&[("AAAA", 20), ("AAAT", 8), ("AATT", 3)], UmiCorrection::Directional), 1);
+        // ...but only while each step has enough reads: 3 + 1 < 2 * 3
+        assert_eq!(count(&[("AAAA", 20), ("AAAT", 3), ("AATT", 3)], UmiCorrection::Directional), 2);
+    }
+}
@@ -1,9 +1,19 @@
+pub mod compression;
+pub mod fasta;
 pub mod fastq;
+pub mod reads;
+pub mod sam;
+pub mod table;
 
 use phf::phf_map;
 
-pub fn reverse_complement(sequence: &str) -> String {
-    sequence.chars().rev().map(|n| COMPLEMENT.get(&n).expect("attempted to reverse-complement non-ATCGN nucleotide")).collect()
+pub fn reverse_complement(sequence: &str) -> Result<String, SequenceError> {
+    sequence.chars().rev().map(|n| complement(n).ok_or(SequenceError::InvalidNucleotide(n))).collect()
+}
+
+/// The complement of an A, C, G, T or N, keeping its case, or `None` for any other character
+pub fn complement(base: char) -> Option<char> {
+    COMPLEMENT.get(&base).copied()
 }
 
 static COMPLEMENT: phf::Map<char, char> = phf_map! {
@@ -12,4 +22,15 @@ static COMPLEMENT: phf::Map<char, char> = phf_map! {
     'T' => 'A',
     'G' => 'C',
     'N' => 'N',
+    'a' => 't',
+    'c' => 'g',
+    't' => 'a',
+    'g' => 'c',
+    'n' => 'n',
 };
+
+#[derive(thiserror::Error, Debug)]
+pub enum SequenceError {
+    #[error("cannot reverse-complement non-ATCGN nucleotide '{0}'")]
+    InvalidNucleotide(char),
+}
@@ -0,0 +1,151 @@
+use std::{fs, io::{self, prelude::*}, path};
+
+/// Compression formats recognised on input and supported on output
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Gzip,
+    Bgzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Identify the compression format of a stream from its first few bytes. Gzip streams are only
+    /// reported as BGZF when enough of the header is available to see the `BC` extra subfield.
+    pub fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            if magic.len() >= 14 && magic[3] & 0x04 != 0 && &magic[12..14] == b"BC" {
+                Self::Bgzip
+            } else {
+                Self::Gzip
+            }
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+
+    /// Guess the intended compression format of an output file from its extension
+    pub fn from_extension(path: &path::Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("gz") | Some("gzip") => Self::Gzip,
+            Some("bgz") | Some("bgzf") => Self::Bgzip,
+            Some("zst") | Some("zstd") => Self::Zstd,
+            _ => Self::None,
+        }
+    }
+}
+
+/// Open a file for reading, transparently decompressing it if it is gzip, BGZF or zstd compressed
+pub fn open(path: &path::Path) -> io::Result<Box<dyn Read + Send>> {
+    decompress(io::BufReader::new(fs::File::open(path)?))
+}
+
+/// Decompress a stream according to the compression format detected from its first few bytes
+fn decompress<R: BufRead + Send + 'static>(mut source: R) -> io::Result<Box<dyn Read + Send>> {
+    let compression = Compression::detect(source.fill_buf()?);
+    Ok(match compression {
+        Compression::None => Box::new(source),
+        // BGZF is a series of concatenated gzip members, so it must be read with a multi-member decoder
+        Compression::Gzip | Compression::Bgzip => Box::new(flate2::bufread::MultiGzDecoder::new(source)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(source)?),
+    })
+}
+
+/// A buffered output file, optionally compressed. [`Writer::finish`] must be called once all data
+/// has been written so that compressed streams are properly terminated.
+pub enum Writer {
+    Plain(io::BufWriter<fs::File>),
+    Gzip(flate2::write::GzEncoder<io::BufWriter<fs::File>>),
+    Bgzip(noodles::bgzf::io::Writer<io::BufWriter<fs::File>>),
+    Zstd(zstd::stream::write::Encoder<'static, io::BufWriter<fs::File>>),
+}
+
+impl Writer {
+    /// Create an output file. If `compression` is `None`, the format is guessed from the file
+    /// extension.
+    pub fn create(path: &path::Path, compression: Option<Compression>) -> io::Result<Self> {
+        let destination = io::BufWriter::new(fs::File::create(path)?);
+        Ok(match compression.unwrap_or_else(|| Compression::from_extension(path)) {
+            Compression::None => Self::Plain(destination),
+            Compression::Gzip => Self::Gzip(flate2::write::GzEncoder::new(destination, flate2::Compression::default())),
+            Compression::Bgzip => Self::Bgzip(noodles::bgzf::io::Writer::new(destination)),
+            Compression::Zstd => Self::Zstd(zstd::stream::write::Encoder::new(destination, 0)?),
+        })
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        let mut destination = match self {
+            Self::Plain(destination) => destination,
+            Self::Gzip(encoder) => encoder.finish()?,
+            Self::Bgzip(encoder) => encoder.finish()?,
+            Self::Zstd(encoder) => encoder.finish()?,
+        };
+        destination.flush()
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(destination) => destination.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Bgzip(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(destination) => destination.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Bgzip(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &[u8] = b"@read1\nACGT\n+\nIIII\n";
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).expect("failed to compress");
+        encoder.finish().expect("failed to compress")
+    }
+
+    fn decompressed(compressed: Vec<u8>) -> Vec<u8> {
+        let mut data = Vec::new();
+        decompress(io::Cursor::new(compressed)).expect("failed to open stream").read_to_end(&mut data).expect("failed to decompress");
+        data
+    }
+
+    #[test]
+    fn formats_are_detected_and_decompressed() {
+        let mut bgzf = noodles::bgzf::io::Writer::new(Vec::new());
+        bgzf.write_all(TEXT).expect("failed to compress");
+        let bgzf = bgzf.finish().expect("failed to compress");
+        let zstd = zstd::encode_all(TEXT, 0).expect("failed to compress");
+
+        for (compressed, compression) in [(gzip(TEXT), Compression::Gzip), (bgzf, Compression::Bgzip), (zstd, Compression::Zstd), (TEXT.to_vec(), Compression::None)] {
+            assert_eq!(Compression::detect(&compressed), compression);
+            assert_eq!(decompressed(compressed), TEXT, "{:?} differs", compression);
+        }
+        // Without the whole header, BGZF cannot be told apart from gzip
+        assert_eq!(Compression::detect(&[0x1f, 0x8b, 0x08, 0x04]), Compression::Gzip);
+        assert_eq!(Compression::detect(b""), Compression::None);
+    }
+
+    #[test]
+    fn every_gzip_member_is_read() {
+        let (first, second) = TEXT.split_at(7);
+        let mut members = gzip(first);
+        members.extend(gzip(second));
+        assert_eq!(decompressed(members), TEXT);
+    }
+}
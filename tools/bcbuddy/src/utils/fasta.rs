@@ -0,0 +1,84 @@
+use std::io::{self, prelude::*};
+
+use crate::utils::fastq::FASTQRecord;
+
+/// Reads records from a FASTA file, whose sequences may be split across several lines. Records
+/// have no quality scores.
+pub struct FASTAReader<R: Read> {
+    source: io::BufReader<R>,
+    buffer: String,
+    next_identifier: Option<String>
+}
+
+impl <R: Read> FASTAReader<R> {
+    pub fn read_fasta(source: R) -> FASTAReader<R> {
+        FASTAReader {
+            source: io::BufReader::new(source),
+            buffer: String::new(),
+            next_identifier: None
+        }
+    }
+}
+
+impl <R: Read> Iterator for FASTAReader<R> {
+    type Item = Result<FASTQRecord, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let identifier = match self.next_identifier.take() {
+            Some(identifier) => identifier,
+            None => {
+                loop {
+                    self.buffer.clear();
+                    match self.source.read_line(&mut self.buffer) {
+                        Err(error) => { return Some(Err(error)); },
+                        Ok(0) => { return None; },
+                        Ok(_) if self.buffer.trim().is_empty() => {},
+                        Ok(_) if self.buffer.starts_with('>') => { break self.buffer[1..].trim_end().to_owned(); },
+                        Ok(_) => { return Some(Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "FASTA sequence does not start with a \">\" header line"))); },
+                    }
+                }
+            }
+        };
+
+        let mut sequence = String::new();
+        loop {
+            self.buffer.clear();
+            match self.source.read_line(&mut self.buffer) {
+                Err(error) => { return Some(Err(error)); },
+                Ok(0) => { break; },
+                Ok(_) if self.buffer.starts_with('>') => {
+                    self.next_identifier = Some(self.buffer[1..].trim_end().to_owned());
+                    break;
+                },
+                Ok(_) => { sequence.push_str(self.buffer.trim()); },
+            }
+        }
+
+        Some(Ok(FASTQRecord {
+            identifier,
+            sequence,
+            quality_scores: None
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequences_may_span_several_lines() {
+        let records: Vec<(String, String)> = FASTAReader::read_fasta(">read1 first\nACGT\nAC\n\n>read2\r\nGG\r\n>read3\n".as_bytes())
+            .map(|record| record.expect("valid record"))
+            .inspect(|record| assert!(record.quality_scores.is_none()))
+            .map(|record| (record.identifier, record.sequence))
+            .collect();
+        assert_eq!(records, [("read1 first".to_string(), "ACGTAC".to_string()), ("read2".to_string(), "GG".to_string()), ("read3".to_string(), String::new())]);
+    }
+
+    #[test]
+    fn sequences_need_a_header() {
+        let mut records = FASTAReader::read_fasta("\nACGT\n>read1\nACGT\n".as_bytes());
+        assert!(matches!(records.next(), Some(Err(error)) if error.kind() == io::ErrorKind::InvalidData));
+    }
+}
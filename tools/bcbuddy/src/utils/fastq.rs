@@ -1,15 +1,23 @@
 use std::{fmt, io::{self, prelude::*}};
 
+use itertools::Itertools;
+
+/// A sequencing read, from any supported input format
 #[derive(Debug)]
 pub struct FASTQRecord {
     pub identifier: String,
     pub sequence: String,
-    pub quality_scores: Vec<u8>
+    /// Phred+33 encoded quality scores, if the input format has them
+    pub quality_scores: Option<Vec<u8>>
 }
 
+/// Formats the record as FASTQ, or as FASTA if it has no quality scores
 impl fmt::Display for FASTQRecord {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "@{}\n{}\n+\n{}\n", self.identifier, self.sequence, unsafe { std::str::from_utf8_unchecked(&self.quality_scores) })
+        match self.quality_scores {
+            Some(ref quality_scores) => write!(f, "@{}\n{}\n+\n{}\n", self.identifier, self.sequence, String::from_utf8_lossy(quality_scores)),
+            None => write!(f, ">{}\n{}\n", self.identifier, self.sequence),
+        }
     }
 }
 
@@ -60,11 +68,216 @@ impl <R: Read> Iterator for FASTQReader<R> {
         };
         self.buffer.clear();
 
+        if quality_scores.len() != sequence.len() {
+            return Some(Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("record \"{}\" has {} bases but {} quality scores", identifier, sequence.len(), quality_scores.len()))));
+        }
+
         Some(Ok(FASTQRecord {
-            identifier: identifier,
-            sequence: sequence,
-            quality_scores: quality_scores
+            identifier,
+            sequence,
+            quality_scores: Some(quality_scores)
         }))
     }
 }
 
+
+/// How to respond when the records read together from several sources do not have the same name
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MateCheck {
+    /// Stop with an error
+    Error,
+    /// Print a warning and keep going
+    Warn,
+    /// Do not compare names
+    Ignore,
+}
+
+/// The number of mismatched mates reported individually before warnings stop
+const MAX_MATE_WARNINGS: usize = 10;
+
+/// The name shared by all mates of a read: the identifier up to the first whitespace (dropping any
+/// Illumina comment), without a trailing `/1`, `/2`, etc.
+pub fn mate_name(identifier: &str) -> &str {
+    let name = identifier.split_ascii_whitespace().next().unwrap_or("");
+    match name.rsplit_once('/') {
+        Some((base, mate)) if mate.len() == 1 && mate.bytes().all(|c| c.is_ascii_digit()) => base,
+        _ => name,
+    }
+}
+
+/// Reads records from several sources in lockstep, yielding one record from each source (e.g. the
+/// mates of a paired-end read) at a time. All sources must contain the same number of records.
+/// Alternatively, reads consecutive records from a single interleaved source as each tuple.
+pub struct FASTQTupleReader<R: Iterator<Item = io::Result<FASTQRecord>>> {
+    readers: Vec<R>,
+    /// The number of consecutive records taken from each source for one tuple
+    records_per_source: usize,
+    mate_check: MateCheck,
+    mismatched_mates: usize
+}
+
+impl <R: Iterator<Item = io::Result<FASTQRecord>>> FASTQTupleReader<R> {
+    pub fn read_fastqs(sources: impl IntoIterator<Item = R>, mate_check: MateCheck) -> FASTQTupleReader<R> {
+        FASTQTupleReader {
+            readers: sources.into_iter().collect(),
+            records_per_source: 1,
+            mate_check,
+            mismatched_mates: 0
+        }
+    }
+
+    /// Read tuples of `num_mates` consecutive records from a single source, such as interleaved
+    /// paired-end FASTQ. The source must contain a whole number of tuples.
+    pub fn read_interleaved(source: R, num_mates: usize, mate_check: MateCheck) -> FASTQTupleReader<R> {
+        FASTQTupleReader {
+            readers: vec![source],
+            records_per_source: num_mates,
+            mate_check,
+            mismatched_mates: 0
+        }
+    }
+
+    /// The number of tuples read so far whose records did not all have the same name
+    pub fn mismatched_mates(&self) -> usize {
+        self.mismatched_mates
+    }
+
+    fn check_mates(&mut self, records: &[FASTQRecord]) -> Result<(), std::io::Error> {
+        if self.mate_check == MateCheck::Ignore || records.is_empty() {
+            return Ok(());
+        }
+
+        let name = mate_name(&records[0].identifier);
+        let mismatch = match records.iter().enumerate().skip(1).find(|(_, record)| mate_name(&record.identifier) != name) {
+            Some((index, record)) => format!("mate names do not match: \"{}\" (source 1) and \"{}\" (source {})", records[0].identifier, record.identifier, index + 1),
+            None => { return Ok(()); }
+        };
+
+        self.mismatched_mates += 1;
+        match self.mate_check {
+            MateCheck::Error => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, mismatch)),
+            _ => {
+                if self.mismatched_mates <= MAX_MATE_WARNINGS {
+                    eprintln!("warning: {}", mismatch);
+                }
+                if self.mismatched_mates == MAX_MATE_WARNINGS {
+                    eprintln!("warning: further mismatched mate names will not be reported individually");
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl <R: Iterator<Item = io::Result<FASTQRecord>>> Iterator for FASTQTupleReader<R> {
+    type Item = Result<Vec<FASTQRecord>, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut records = Vec::with_capacity(self.readers.len() * self.records_per_source);
+        let mut exhausted = Vec::new();
+        for (index, reader) in self.readers.iter_mut().enumerate() {
+            for mate in 0..self.records_per_source {
+                match reader.next() {
+                    Some(Ok(record)) => records.push(record),
+                    Some(Err(error)) => { return Some(Err(error)); },
+                    None if mate == 0 => { exhausted.push(index + 1); break; },
+                    None => { return Some(Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, format!("source {} ended partway through a set of {} interleaved records", index + 1, self.records_per_source)))); },
+                }
+            }
+        }
+
+        if exhausted.len() == self.readers.len() {
+            return None;
+        } else if !exhausted.is_empty() {
+            return Some(Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, format!("source {} ended before the other sources", exhausted.iter().join(", ")))));
+        }
+
+        Some(self.check_mates(&records).map(|_| records))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A FASTQ source with one record for each identifier
+    fn source(identifiers: &[&str]) -> FASTQReader<io::Cursor<Vec<u8>>> {
+        let text: String = identifiers.iter().map(|identifier| format!("@{}\nACGT\n+\nIIII\n", identifier)).collect();
+        FASTQReader::read_fastq(io::Cursor::new(text.into_bytes()))
+    }
+
+    fn identifiers(records: &[FASTQRecord]) -> Vec<&str> {
+        records.iter().map(|record| record.identifier.as_str()).collect()
+    }
+
+    #[test]
+    fn mate_names_drop_mate_numbers_and_comments() {
+        assert_eq!(mate_name("read1/1"), "read1");
+        assert_eq!(mate_name("read1/2"), "read1");
+        assert_eq!(mate_name("M00123:7:000:1:1101:15589:1331 1:N:0:ACGTACGT"), "M00123:7:000:1:1101:15589:1331");
+        assert_eq!(mate_name("read1/1 extra comment"), "read1");
+        // Only a single trailing digit is a mate number
+        assert_eq!(mate_name("sample/12"), "sample/12");
+        assert_eq!(mate_name("sample/a"), "sample/a");
+        assert_eq!(mate_name(""), "");
+    }
+
+    #[test]
+    fn lockstep_sources_give_one_record_each() {
+        let tuples: Vec<Vec<FASTQRecord>> = FASTQTupleReader::read_fastqs([source(&["a/1", "b/1"]), source(&["a/2", "b/2"])], MateCheck::Error).collect::<Result<_, _>>().expect("mates match");
+        assert_eq!(tuples.iter().map(|tuple| identifiers(tuple)).collect::<Vec<_>>(), [["a/1", "a/2"], ["b/1", "b/2"]]);
+    }
+
+    #[test]
+    fn mismatched_mate_names_follow_the_mate_check() {
+        let sources = || [source(&["a/1", "b/1"]), source(&["a/2", "c/2"])];
+        let mut reader = FASTQTupleReader::read_fastqs(sources(), MateCheck::Error);
+        assert!(reader.next().is_some_and(|tuple| tuple.is_ok()));
+        let error = reader.next().and_then(Result::err).expect("mismatched mates are an error");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("\"c/2\" (source 2)"), "{}", error);
+
+        for mate_check in [MateCheck::Warn, MateCheck::Ignore] {
+            let mut reader = FASTQTupleReader::read_fastqs(sources(), mate_check);
+            assert_eq!(reader.by_ref().collect::<Result<Vec<_>, _>>().expect("mismatched mates are allowed").len(), 2);
+            assert_eq!(reader.mismatched_mates(), if mate_check == MateCheck::Warn { 1 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn interleaved_sources_give_consecutive_records() {
+        let reader = FASTQTupleReader::read_interleaved(source(&["a/1", "a/2", "b/1", "b/2"]), 2, MateCheck::Error);
+        let tuples: Vec<Vec<FASTQRecord>> = reader.collect::<Result<_, _>>().expect("mates match");
+        assert_eq!(tuples.iter().map(|tuple| identifiers(tuple)).collect::<Vec<_>>(), [["a/1", "a/2"], ["b/1", "b/2"]]);
+
+        // Consecutive records from different reads are mismatched mates
+        let mut reader = FASTQTupleReader::read_interleaved(source(&["a/1", "b/1"]), 2, MateCheck::Error);
+        assert!(reader.next().is_some_and(|tuple| tuple.is_err()));
+    }
+
+    #[test]
+    fn interleaved_sources_must_have_whole_tuples() {
+        let mut reader = FASTQTupleReader::read_interleaved(source(&["a/1", "a/2", "b/1"]), 2, MateCheck::Error);
+        assert!(reader.next().is_some_and(|tuple| tuple.is_ok()));
+        let error = reader.next().and_then(Result::err).expect("an odd record count is an error");
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(error.to_string(), "source 1 ended partway through a set of 2 interleaved records");
+        // Three records make a whole tuple of three mates
+        let reader = FASTQTupleReader::read_interleaved(source(&["a/1", "a/2", "a/3"]), 3, MateCheck::Error);
+        assert_eq!(reader.collect::<Result<Vec<_>, _>>().expect("mates match").len(), 1);
+    }
+
+    #[test]
+    fn sources_must_have_the_same_length() {
+        let mut reader = FASTQTupleReader::read_fastqs([source(&["a", "b"]), source(&["a"]), source(&["a"])], MateCheck::Error);
+        assert!(reader.next().is_some_and(|tuple| tuple.is_ok()));
+        let error = reader.next().and_then(Result::err).expect("a shorter source is an error");
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(error.to_string(), "source 2, 3 ended before the other sources");
+        // The first source ending first is reported the same way
+        let mut reader = FASTQTupleReader::read_fastqs([source(&["a"]), source(&["a", "b"])], MateCheck::Error);
+        assert!(reader.next().is_some_and(|tuple| tuple.is_ok()));
+        assert!(reader.next().is_some_and(|tuple| tuple.is_err()));
+    }
+}
+
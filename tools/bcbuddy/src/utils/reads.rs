@@ -0,0 +1,107 @@
+use std::{fmt, io::{self, prelude::*}, path};
+
+use crate::utils::{compression, fasta, fastq, sam};
+
+/// An iterator over the reads in a source, whatever its format
+pub type RecordReader = Box<dyn Iterator<Item = io::Result<fastq::FASTQRecord>> + Send>;
+
+/// Read file formats recognised on input
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadFormat {
+    Fastq,
+    Fasta,
+    Sam,
+    /// BAM, including unaligned BAM
+    Bam,
+}
+
+impl ReadFormat {
+    /// Identify the format of a (decompressed) stream from its first few bytes, or `None` if it is
+    /// not recognised. An empty stream is treated as FASTQ.
+    pub fn detect(start: &[u8]) -> Option<Self> {
+        const SAM_HEADER_RECORDS: [&[u8]; 5] = [b"@HD\t", b"@SQ\t", b"@RG\t", b"@PG\t", b"@CO\t"];
+        if start.is_empty() {
+            Some(Self::Fastq)
+        } else if start.starts_with(b"BAM\x01") {
+            Some(Self::Bam)
+        } else if SAM_HEADER_RECORDS.iter().any(|record| start.starts_with(record)) {
+            Some(Self::Sam)
+        } else if start.starts_with(b"@") {
+            Some(Self::Fastq)
+        } else if start.starts_with(b">") {
+            Some(Self::Fasta)
+        } else if start.split(|byte| *byte == b'\n').next().is_some_and(|line| line.split(|byte| *byte == b'\t').count() >= 11) {
+            // A SAM file without a header, starting with an alignment record of 11 or more fields
+            Some(Self::Sam)
+        } else {
+            None
+        }
+    }
+
+    pub fn has_quality_scores(&self) -> bool {
+        *self != Self::Fasta
+    }
+}
+
+impl fmt::Display for ReadFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fastq => write!(f, "FASTQ"),
+            Self::Fasta => write!(f, "FASTA"),
+            Self::Sam => write!(f, "SAM"),
+            Self::Bam => write!(f, "BAM"),
+        }
+    }
+}
+
+/// Open a file of reads, detecting its compression and then its format from its contents
+pub fn open(path: &path::Path) -> io::Result<(ReadFormat, RecordReader)> {
+    let mut source = io::BufReader::new(compression::open(path)?);
+    let format = match ReadFormat::detect(source.fill_buf()?) {
+        Some(format) => format,
+        None => { return Err(io::Error::new(io::ErrorKind::InvalidData, format!("\"{}\" is not a FASTQ, FASTA, SAM or BAM file", path.display()))); }
+    };
+    let reader: RecordReader = match format {
+        ReadFormat::Fastq => Box::new(fastq::FASTQReader::read_fastq(source)),
+        ReadFormat::Fasta => Box::new(fasta::FASTAReader::read_fasta(source)),
+        ReadFormat::Sam => Box::new(sam::SAMReader::read_sam(source)?),
+        ReadFormat::Bam => Box::new(sam::BAMReader::read_bam(source)?),
+    };
+    Ok((format, reader))
+}
+
+/// Open several files of reads (e.g. sequencing lanes), which must all have the same format, and
+/// read them one after another
+pub fn open_all<'a>(paths: impl IntoIterator<Item = &'a path::Path>) -> io::Result<(ReadFormat, RecordReader)> {
+    let mut combined: Option<(ReadFormat, RecordReader)> = None;
+    for path in paths {
+        let (format, reader) = open(path)?;
+        combined = match combined {
+            None => Some((format, reader)),
+            Some((combined_format, combined_reader)) if combined_format == format => Some((format, Box::new(combined_reader.chain(reader)))),
+            Some((combined_format, _)) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("\"{}\" is {}, but the files read before it are {}", path.display(), format, combined_format)));
+            }
+        };
+    }
+    Ok(combined.unwrap_or_else(|| (ReadFormat::Fastq, Box::new(std::iter::empty()))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_are_detected_from_their_first_bytes() {
+        assert_eq!(ReadFormat::detect(b"@read1\nACGT\n+\nIIII\n"), Some(ReadFormat::Fastq));
+        assert_eq!(ReadFormat::detect(b""), Some(ReadFormat::Fastq));
+        assert_eq!(ReadFormat::detect(b">read1\nACGT\n"), Some(ReadFormat::Fasta));
+        assert_eq!(ReadFormat::detect(b"@HD\tVN:1.6\n"), Some(ReadFormat::Sam));
+        assert_eq!(ReadFormat::detect(b"@SQ\tSN:amp\tLN:100\n"), Some(ReadFormat::Sam));
+        assert_eq!(ReadFormat::detect(b"read\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII\n"), Some(ReadFormat::Sam));
+        assert_eq!(ReadFormat::detect(b"BAM\x01\x00\x00"), Some(ReadFormat::Bam));
+        assert_eq!(ReadFormat::detect(b"read\tACGT\n"), None);
+        // A FASTQ read whose name happens to start like a SAM header record is still FASTQ
+        assert_eq!(ReadFormat::detect(b"@HDread\nACGT\n+\nIIII\n"), Some(ReadFormat::Fastq));
+    }
+}
@@ -0,0 +1,178 @@
+use std::io::{self, prelude::*};
+
+use noodles::{bam, sam};
+
+use crate::{quality, utils::{self, fastq::FASTQRecord}};
+
+/// Reads records from a SAM file, skipping secondary and supplementary alignments so that each
+/// read is seen once. Reads aligned to the reverse strand are returned as they were sequenced.
+pub struct SAMReader<R: BufRead> {
+    source: sam::io::Reader<R>,
+    record: sam::Record
+}
+
+impl <R: BufRead> SAMReader<R> {
+    pub fn read_sam(source: R) -> io::Result<SAMReader<R>> {
+        let mut source = sam::io::Reader::new(source);
+        source.read_header()?;
+        Ok(SAMReader {
+            source,
+            record: sam::Record::default()
+        })
+    }
+}
+
+impl <R: BufRead> Iterator for SAMReader<R> {
+    type Item = Result<FASTQRecord, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.source.read_record(&mut self.record) {
+                Err(error) => { return Some(Err(error)); },
+                Ok(0) => { return None; },
+                Ok(_) => {},
+            }
+            let flags = match self.record.flags() {
+                Ok(flags) => flags,
+                Err(error) => { return Some(Err(error)); }
+            };
+            if flags.is_secondary() || flags.is_supplementary() {
+                continue;
+            }
+            let quality_scores = self.record.quality_scores();
+            let quality_scores = match quality_scores.as_ref() {
+                b"" | b"*" => None,
+                quality_scores => Some(quality_scores.to_vec()),
+            };
+            return Some(as_sequenced(
+                self.record.name().map_or_else(String::new, |name| name.to_string()),
+                String::from_utf8_lossy(self.record.sequence().as_ref()).into_owned(),
+                quality_scores,
+                flags.is_reverse_complemented()
+            ));
+        }
+    }
+}
+
+/// Reads records from a BAM file, including unaligned BAM, in the same way as [`SAMReader`]. The
+/// source must already be decompressed.
+pub struct BAMReader<R: Read> {
+    source: bam::io::Reader<R>,
+    record: bam::Record
+}
+
+impl <R: Read> BAMReader<R> {
+    pub fn read_bam(source: R) -> io::Result<BAMReader<R>> {
+        let mut source = bam::io::Reader::from(source);
+        source.read_header()?;
+        Ok(BAMReader {
+            source,
+            record: bam::Record::default()
+        })
+    }
+}
+
+impl <R: Read> Iterator for BAMReader<R> {
+    type Item = Result<FASTQRecord, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.source.read_record(&mut self.record) {
+                Err(error) => { return Some(Err(error)); },
+                Ok(0) => { return None; },
+                Ok(_) => {},
+            }
+            let flags = self.record.flags();
+            if flags.is_secondary() || flags.is_supplementary() {
+                continue;
+            }
+            // BAM stores raw Phred scores, with 0xFF throughout when they are missing
+            let quality_scores = self.record.quality_scores();
+            let quality_scores = if quality_scores.is_empty() || quality_scores.iter().all(|score| score == 0xff) {
+                None
+            } else {
+                Some(quality_scores.iter().map(|score| score.saturating_add(quality::PHRED_OFFSET)).collect())
+            };
+            return Some(as_sequenced(
+                self.record.name().map_or_else(String::new, |name| name.to_string()),
+                self.record.sequence().iter().map(char::from).collect(),
+                quality_scores,
+                flags.is_reverse_complemented()
+            ));
+        }
+    }
+}
+
+/// Build a record, undoing the reverse complement applied to reads aligned to the reverse strand.
+/// Bases with no complement here (e.g. IUPAC codes other than N) become N, so that they fail to
+/// match like any other unexpected base rather than stopping the run.
+fn as_sequenced(identifier: String, sequence: String, quality_scores: Option<Vec<u8>>, is_reverse_complemented: bool) -> io::Result<FASTQRecord> {
+    if let Some(ref quality_scores) = quality_scores {
+        if quality_scores.len() != sequence.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("record \"{}\" has {} bases but {} quality scores", identifier, sequence.len(), quality_scores.len())));
+        }
+    }
+    if !is_reverse_complemented {
+        return Ok(FASTQRecord { identifier, sequence, quality_scores });
+    }
+    let sequence = sequence.chars().rev().map(|base| utils::complement(base).unwrap_or('N')).collect();
+    let quality_scores = quality_scores.map(|quality_scores| quality_scores.into_iter().rev().collect());
+    Ok(FASTQRecord { identifier, sequence, quality_scores })
+}
+
+#[cfg(test)]
+mod tests {
+    use noodles::sam::alignment::io::Write as _;
+
+    use super::*;
+
+    const SAM: &str = "@HD\tVN:1.6\n@SQ\tSN:amp\tLN:100\n\
+        forward\t0\tamp\t1\t60\t4M\t*\t0\t0\tACGT\tABCD\n\
+        reverse\t16\tamp\t1\t60\t4M\t*\t0\t0\tAACG\tABCD\n\
+        secondary\t256\tamp\t1\t0\t4M\t*\t0\t0\t*\t*\n\
+        supplementary\t2048\tamp\t1\t60\t4M\t*\t0\t0\tACGT\tABCD\n\
+        ambiguous\t16\tamp\t1\t60\t4M\t*\t0\t0\tARCG\t*\n\
+        unaligned\t4\t*\t0\t0\t*\t*\t0\t0\tGGGG\tIIII\n";
+
+    /// The identifier, sequence and quality scores of each record
+    fn summarise(records: impl Iterator<Item = io::Result<FASTQRecord>>) -> Vec<(String, String, Option<Vec<u8>>)> {
+        records.map(|record| record.expect("valid record")).map(|record| (record.identifier, record.sequence, record.quality_scores)).collect()
+    }
+
+    fn expected() -> Vec<(String, String, Option<Vec<u8>>)> {
+        vec![
+            ("forward".to_string(), "ACGT".to_string(), Some(b"ABCD".to_vec())),
+            // Reverse-strand reads are returned as sequenced
+            ("reverse".to_string(), "CGTT".to_string(), Some(b"DCBA".to_vec())),
+            ("ambiguous".to_string(), "CGNT".to_string(), None),
+            ("unaligned".to_string(), "GGGG".to_string(), Some(b"IIII".to_vec())),
+        ]
+    }
+
+    #[test]
+    fn sam_records_are_read_as_sequenced() {
+        let records = SAMReader::read_sam(SAM.as_bytes()).expect("valid SAM header");
+        assert_eq!(summarise(records), expected());
+    }
+
+    #[test]
+    fn bam_records_are_read_as_sequenced() {
+        let mut sam_reader = sam::io::Reader::new(SAM.as_bytes());
+        let header = sam_reader.read_header().expect("valid SAM header");
+        let mut bam_writer = bam::io::Writer::from(Vec::new());
+        bam_writer.write_header(&header).expect("failed to write BAM header");
+        for record in sam_reader.record_bufs(&header) {
+            bam_writer.write_alignment_record(&header, &record.expect("valid SAM record")).expect("failed to write BAM record");
+        }
+        let bam = bam_writer.into_inner();
+        let records = BAMReader::read_bam(bam.as_slice()).expect("valid BAM header");
+        assert_eq!(summarise(records), expected());
+    }
+
+    #[test]
+    fn mismatched_quality_scores_are_rejected() {
+        let sam = "@HD\tVN:1.6\nread\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tABC\n";
+        let mut records = SAMReader::read_sam(sam.as_bytes()).expect("valid SAM header");
+        assert!(matches!(records.next(), Some(Err(error)) if error.kind() == io::ErrorKind::InvalidData));
+    }
+}
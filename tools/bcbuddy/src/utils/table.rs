@@ -0,0 +1,405 @@
+use std::{borrow::Cow, fs, io::{self, prelude::*}, path, sync::Arc};
+
+use arrow_array::{builder::{ArrayBuilder, Float64Builder, StringBuilder, UInt64Builder}, cast::AsArray, types::{Float64Type, UInt64Type}, Array, RecordBatchReader};
+use itertools::Itertools;
+
+use crate::utils::compression::{self, Compression};
+
+/// File formats for tables of extracted barcodes
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TableFormat {
+    /// Tab-separated text with a header line
+    Tsv,
+    /// Apache Parquet
+    Parquet,
+    /// The Arrow IPC file format (also known as Feather version 2)
+    Arrow,
+}
+
+impl TableFormat {
+    /// Guess the intended format of an output file from its extension, defaulting to TSV
+    pub fn from_extension(path: &path::Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("parquet") | Some("pq") => Self::Parquet,
+            Some("arrow") | Some("ipc") | Some("feather") => Self::Arrow,
+            _ => Self::Tsv,
+        }
+    }
+}
+
+/// The type of values in a table column
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnType {
+    Text,
+    Integer,
+    Float,
+}
+
+/// A single value in a table row; its variant must match the type of its column
+#[derive(Debug)]
+pub enum Value<'a> {
+    Text(Cow<'a, str>),
+    Integer(u64),
+    Float(f64),
+    Missing,
+}
+
+/// A table of extracted barcodes being written to a file, one row at a time. [`TableWriter::finish`]
+/// must be called once all rows have been written.
+pub struct TableWriter {
+    num_columns: usize,
+    destination: Destination,
+}
+
+enum Destination {
+    Tsv(compression::Writer),
+    Parquet(parquet::arrow::ArrowWriter<fs::File>, RecordBatchBuilder),
+    Arrow(arrow_ipc::writer::FileWriter<io::BufWriter<fs::File>>, RecordBatchBuilder),
+}
+
+/// Buffers rows for a columnar format until there are enough to write as a record batch
+struct RecordBatchBuilder {
+    schema: arrow_schema::SchemaRef,
+    columns: Vec<Box<dyn ArrayBuilder>>,
+    batch_size: usize,
+}
+
+impl TableWriter {
+    /// Create a table with the given column names and types. If `format` is `None`, it is guessed
+    /// from the file extension. `compression` applies to the whole file for TSV (see
+    /// [`compression::Writer::create`]) and to each column chunk for Parquet, where it defaults to
+    /// zstd; Arrow IPC files are always uncompressed. Columnar formats are written in batches of
+    /// `row_group_size` rows.
+    pub fn create(path: &path::Path, columns: &[(String, ColumnType)], format: Option<TableFormat>, compression: Option<Compression>, row_group_size: usize) -> Result<Self, TableError> {
+        let destination = match format.unwrap_or_else(|| TableFormat::from_extension(path)) {
+            TableFormat::Tsv => {
+                let mut out = compression::Writer::create(path, compression)?;
+                writeln!(out, "{}", columns.iter().map(|(name, _)| name).join("\t"))?;
+                Destination::Tsv(out)
+            },
+            TableFormat::Parquet => {
+                let parquet_compression = match compression {
+                    Some(Compression::None) => parquet::basic::Compression::UNCOMPRESSED,
+                    Some(Compression::Gzip) | Some(Compression::Bgzip) => parquet::basic::Compression::GZIP(Default::default()),
+                    Some(Compression::Zstd) | None => parquet::basic::Compression::ZSTD(Default::default()),
+                };
+                let properties = parquet::file::properties::WriterProperties::builder()
+                    .set_compression(parquet_compression)
+                    .set_max_row_group_size(row_group_size)
+                    .build();
+                let batch = RecordBatchBuilder::new(columns, row_group_size);
+                let writer = parquet::arrow::ArrowWriter::try_new(fs::File::create(path)?, batch.schema.clone(), Some(properties))?;
+                Destination::Parquet(writer, batch)
+            },
+            TableFormat::Arrow => {
+                if matches!(compression, Some(compression) if compression != Compression::None) {
+                    return Err(TableError::UnsupportedCompression);
+                }
+                let batch = RecordBatchBuilder::new(columns, row_group_size);
+                let writer = arrow_ipc::writer::FileWriter::try_new(io::BufWriter::new(fs::File::create(path)?), &batch.schema)?;
+                Destination::Arrow(writer, batch)
+            },
+        };
+        Ok(Self {
+            num_columns: columns.len(),
+            destination,
+        })
+    }
+
+    pub fn num_columns(&self) -> usize {
+        self.num_columns
+    }
+
+    pub fn write_row(&mut self, row: &[Value]) -> Result<(), TableError> {
+        debug_assert_eq!(row.len(), self.num_columns);
+        match self.destination {
+            Destination::Tsv(ref mut out) => {
+                for (index, value) in row.iter().enumerate() {
+                    if index > 0 {
+                        write!(out, "\t")?;
+                    }
+                    match value {
+                        Value::Text(text) => write!(out, "{}", text)?,
+                        Value::Integer(integer) => write!(out, "{}", integer)?,
+                        Value::Float(float) => write!(out, "{}", float)?,
+                        Value::Missing => {},
+                    }
+                }
+                writeln!(out)?;
+            },
+            Destination::Parquet(ref mut writer, ref mut batch) => {
+                if let Some(record_batch) = batch.push(row)? {
+                    writer.write(&record_batch)?;
+                }
+            },
+            Destination::Arrow(ref mut writer, ref mut batch) => {
+                if let Some(record_batch) = batch.push(row)? {
+                    writer.write(&record_batch)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), TableError> {
+        match self.destination {
+            Destination::Tsv(out) => { out.finish()?; },
+            Destination::Parquet(mut writer, mut batch) => {
+                if let Some(record_batch) = batch.finish()? {
+                    writer.write(&record_batch)?;
+                }
+                writer.close()?;
+            },
+            Destination::Arrow(mut writer, mut batch) => {
+                if let Some(record_batch) = batch.finish()? {
+                    writer.write(&record_batch)?;
+                }
+                writer.finish()?;
+                writer.into_inner()?.flush()?;
+            },
+        }
+        Ok(())
+    }
+}
+
+/// A table of extracted barcodes being read from a file, one row at a time. Each value is given as
+/// text, as it would be written to TSV, with missing values empty.
+pub struct TableReader {
+    columns: Vec<String>,
+    source: Source,
+}
+
+enum Source {
+    /// The remaining lines, and the number of the last line read
+    Tsv(io::Lines<io::BufReader<Box<dyn Read + Send>>>, usize),
+    /// The remaining record batches, the current batch, and the index of the next row within it
+    Batches(Box<dyn Iterator<Item = Result<arrow_array::RecordBatch, arrow_schema::ArrowError>>>, Option<arrow_array::RecordBatch>, usize),
+}
+
+impl TableReader {
+    /// Open a table written by [`TableWriter`]. If `format` is `None`, it is guessed from the file
+    /// extension. TSV files may be compressed (see [`compression::open`]).
+    pub fn open(path: &path::Path, format: Option<TableFormat>) -> Result<Self, TableReadError> {
+        match format.unwrap_or_else(|| TableFormat::from_extension(path)) {
+            TableFormat::Tsv => {
+                let mut lines = io::BufReader::new(compression::open(path)?).lines();
+                let header = lines.next().ok_or(TableReadError::MissingHeader)??;
+                Ok(Self {
+                    columns: header.trim_end_matches(['\r', '\n']).split('\t').map(str::to_string).collect(),
+                    source: Source::Tsv(lines, 1),
+                })
+            },
+            TableFormat::Parquet => {
+                let reader = parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(fs::File::open(path)?)?.build()?;
+                Self::from_batches(&reader.schema(), Box::new(reader))
+            },
+            TableFormat::Arrow => {
+                let reader = arrow_ipc::reader::FileReader::try_new(io::BufReader::new(fs::File::open(path)?), None)?;
+                Self::from_batches(&reader.schema(), Box::new(reader))
+            },
+        }
+    }
+
+    fn from_batches(schema: &arrow_schema::Schema, batches: Box<dyn Iterator<Item = Result<arrow_array::RecordBatch, arrow_schema::ArrowError>>>) -> Result<Self, TableReadError> {
+        for field in schema.fields() {
+            if !matches!(field.data_type(), arrow_schema::DataType::Utf8 | arrow_schema::DataType::UInt64 | arrow_schema::DataType::Float64) {
+                return Err(TableReadError::UnsupportedColumnType(field.name().clone(), field.data_type().clone()));
+            }
+        }
+        Ok(Self {
+            columns: schema.fields().iter().map(|field| field.name().clone()).collect(),
+            source: Source::Batches(batches, None, 0),
+        })
+    }
+
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+}
+
+impl Iterator for TableReader {
+    type Item = Result<Vec<String>, TableReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.source {
+            Source::Tsv(ref mut lines, ref mut line_number) => {
+                let line = match lines.next()? {
+                    Ok(line) => line,
+                    Err(error) => { return Some(Err(error.into())); }
+                };
+                *line_number += 1;
+                let fields: Vec<String> = line.trim_end_matches(['\r', '\n']).split('\t').map(str::to_string).collect();
+                if fields.len() != self.columns.len() {
+                    return Some(Err(TableReadError::MalformedRow(*line_number, self.columns.len(), fields.len())));
+                }
+                Some(Ok(fields))
+            },
+            Source::Batches(ref mut batches, ref mut batch, ref mut row) => loop {
+                if let Some(batch) = batch.as_ref().filter(|batch| *row < batch.num_rows()) {
+                    let fields = batch.columns().iter().map(|column| text_value(column, *row)).collect();
+                    *row += 1;
+                    return Some(Ok(fields));
+                }
+                match batches.next()? {
+                    Ok(next_batch) => {
+                        *batch = Some(next_batch);
+                        *row = 0;
+                    },
+                    Err(error) => { return Some(Err(error.into())); }
+                }
+            },
+        }
+    }
+}
+
+/// A value from a column of one of the types [`TableWriter`] writes, as it would be written to TSV
+fn text_value(column: &dyn Array, row: usize) -> String {
+    if column.is_null(row) {
+        return String::new();
+    }
+    match column.data_type() {
+        arrow_schema::DataType::Utf8 => column.as_string::<i32>().value(row).to_string(),
+        arrow_schema::DataType::UInt64 => column.as_primitive::<UInt64Type>().value(row).to_string(),
+        arrow_schema::DataType::Float64 => column.as_primitive::<Float64Type>().value(row).to_string(),
+        data_type => unreachable!("column type {} is checked when the table is opened", data_type),
+    }
+}
+
+impl RecordBatchBuilder {
+    fn new(columns: &[(String, ColumnType)], batch_size: usize) -> Self {
+        let fields: Vec<arrow_schema::Field> = columns.iter().map(|(name, column_type)| {
+            let data_type = match column_type {
+                ColumnType::Text => arrow_schema::DataType::Utf8,
+                ColumnType::Integer => arrow_schema::DataType::UInt64,
+                ColumnType::Float => arrow_schema::DataType::Float64,
+            };
+            arrow_schema::Field::new(name, data_type, true)
+        }).collect();
+        Self {
+            schema: Arc::new(arrow_schema::Schema::new(fields)),
+            columns: columns.iter().map(|(_, column_type)| -> Box<dyn ArrayBuilder> {
+                match column_type {
+                    ColumnType::Text => Box::new(StringBuilder::new()),
+                    ColumnType::Integer => Box::new(UInt64Builder::new()),
+                    ColumnType::Float => Box::new(Float64Builder::new()),
+                }
+            }).collect(),
+            batch_size: batch_size.max(1),
+        }
+    }
+
+    /// Add a row, returning a record batch if this makes a full batch
+    fn push(&mut self, row: &[Value]) -> Result<Option<arrow_array::RecordBatch>, TableError> {
+        for (column, value) in self.columns.iter_mut().zip(row.iter()) {
+            let any_column = column.as_any_mut();
+            if let Some(column) = any_column.downcast_mut::<StringBuilder>() {
+                match value {
+                    Value::Text(text) => column.append_value(text),
+                    _ => column.append_null(),
+                }
+            } else if let Some(column) = any_column.downcast_mut::<UInt64Builder>() {
+                match value {
+                    Value::Integer(integer) => column.append_value(*integer),
+                    _ => column.append_null(),
+                }
+            } else if let Some(column) = any_column.downcast_mut::<Float64Builder>() {
+                match value {
+                    Value::Float(float) => column.append_value(*float),
+                    _ => column.append_null(),
+                }
+            }
+        }
+        if self.columns.first().map_or(0, |column| column.len()) >= self.batch_size {
+            self.finish()
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Build a record batch from all buffered rows, if there are any
+    fn finish(&mut self) -> Result<Option<arrow_array::RecordBatch>, TableError> {
+        if self.columns.first().is_none_or(|column| column.is_empty()) {
+            return Ok(None);
+        }
+        let arrays = self.columns.iter_mut().map(|column| column.finish()).collect();
+        Ok(Some(arrow_array::RecordBatch::try_new(self.schema.clone(), arrays)?))
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TableError {
+    #[error("failed to write output table")]
+    Writing {
+        #[from]
+        source: io::Error
+    },
+    #[error("failed to write Arrow output table")]
+    Arrow {
+        #[from]
+        source: arrow_schema::ArrowError
+    },
+    #[error("failed to write Parquet output table")]
+    Parquet {
+        #[from]
+        source: parquet::errors::ParquetError
+    },
+    #[error("Arrow IPC output cannot be compressed")]
+    UnsupportedCompression,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TableReadError {
+    #[error("failed to read table")]
+    Reading {
+        #[from]
+        source: io::Error
+    },
+    #[error("failed to read Arrow table")]
+    Arrow {
+        #[from]
+        source: arrow_schema::ArrowError
+    },
+    #[error("failed to read Parquet table")]
+    Parquet {
+        #[from]
+        source: parquet::errors::ParquetError
+    },
+    #[error("table is missing its header line")]
+    MissingHeader,
+    #[error("line {0} of table should have {1} fields but has {2}")]
+    MalformedRow(usize, usize, usize),
+    #[error("column \"{0}\" has type {1}, which is not text or a number")]
+    UnsupportedColumnType(String, arrow_schema::DataType),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tables_are_read_back_as_written() {
+        let directory = tempfile::tempdir().expect("failed to create temporary directory");
+        let columns = [("BC".to_string(), ColumnType::Text), ("read_count".to_string(), ColumnType::Integer), ("BC_mean_quality".to_string(), ColumnType::Float)];
+        let rows = [
+            [Value::Text("ACGT".into()), Value::Integer(3), Value::Float(30.5)],
+            [Value::Text("".into()), Value::Integer(0), Value::Missing],
+            [Value::Missing, Value::Missing, Value::Float(2.0)],
+        ];
+        let expected = [["ACGT", "3", "30.5"], ["", "0", ""], ["", "", "2"]];
+        for (name, compression) in [("table.tsv", None), ("table.tsv.zst", Some(Compression::Zstd)), ("table.parquet", None), ("table.arrow", None)] {
+            let path = directory.path().join(name);
+            // A row group size of 2 splits the rows across batches
+            let mut table = TableWriter::create(&path, &columns, None, compression, 2).expect("failed to create table");
+            for row in rows.iter() {
+                table.write_row(row).expect("failed to write row");
+            }
+            table.finish().expect("failed to finish table");
+
+            let table = TableReader::open(&path, None).expect("failed to open table");
+            assert_eq!(table.columns(), ["BC", "read_count", "BC_mean_quality"]);
+            let read_rows = table.collect::<Result<Vec<_>, _>>().expect("failed to read table");
+            assert_eq!(read_rows, expected, "{} differs", name);
+        }
+    }
+}
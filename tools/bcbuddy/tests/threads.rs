@@ -0,0 +1,67 @@
+use std::{fs, path::Path, process::Command};
+
+/// The number of read pairs, enough for several batches per worker thread
+const NUM_READS: usize = 30_000;
+
+/// Write paired FASTQ files in which most reads match `ACGT(?P<BC1>[ACGT]{8})` and
+/// `TTGG(?P<BC2>[ACGT]{6})`, using a fixed linear congruential generator so runs are repeatable
+fn write_reads(directory: &Path) {
+    let mut state: u64 = 0x2545f4914f6cdd1d;
+    let mut next_base = || {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        b"ACGT"[(state >> 62) as usize] as char
+    };
+    let mut read1 = String::new();
+    let mut read2 = String::new();
+    for index in 0..NUM_READS {
+        let barcode1: String = (0..8).map(|_| next_base()).collect();
+        let barcode2: String = (0..6).map(|_| next_base()).collect();
+        // Every seventh read lacks the first anchor, and every eleventh the second
+        let anchor1 = if index % 7 == 0 { "AAAA" } else { "ACGT" };
+        let anchor2 = if index % 11 == 0 { "CCCC" } else { "TTGG" };
+        let sequence1 = format!("{}{}CC", anchor1, barcode1);
+        let sequence2 = format!("{}{}AA", anchor2, barcode2);
+        read1.push_str(&format!("@read{}/1\n{}\n+\n{}\n", index, sequence1, "I".repeat(sequence1.len())));
+        read2.push_str(&format!("@read{}/2\n{}\n+\n{}\n", index, sequence2, "I".repeat(sequence2.len())));
+    }
+    fs::write(directory.join("R1.fastq"), read1).expect("failed to write read 1");
+    fs::write(directory.join("R2.fastq"), read2).expect("failed to write read 2");
+}
+
+/// Run bcbuddy over the test reads with the given number of threads, returning the contents of the
+/// output file and of both unmatched read files
+fn run(directory: &Path, threads: usize, count: bool) -> Vec<Vec<u8>> {
+    let prefix = format!("threads{}_{}", threads, if count { "count" } else { "reads" });
+    let output = directory.join(format!("{}.tsv", prefix));
+    let unmatched = [directory.join(format!("{}_unmatched_R1.fastq", prefix)), directory.join(format!("{}_unmatched_R2.fastq", prefix))];
+    let status = Command::new(env!("CARGO_BIN_EXE_bcbuddy"))
+        .arg("--source").arg(directory.join("R1.fastq"))
+        .arg("--source").arg(directory.join("R2.fastq"))
+        .args(["--regex", "ACGT(?P<BC1>[ACGT]{8})", "--regex", "TTGG(?P<BC2>[ACGT]{6})"])
+        // Read IDs cannot be written with --count
+        .arg(if count { "--count" } else { "--output-read-ids" })
+        .arg("--output").arg(&output)
+        .arg("--unmatched-reads").arg(&unmatched[0])
+        .arg("--unmatched-reads").arg(&unmatched[1])
+        .arg("--threads").arg(threads.to_string())
+        .status()
+        .expect("failed to run bcbuddy");
+    assert!(status.success(), "bcbuddy failed with {} threads", threads);
+    std::iter::once(&output).chain(unmatched.iter()).map(|path| fs::read(path).expect("failed to read output")).collect()
+}
+
+#[test]
+fn multithreaded_output_matches_single_threaded() {
+    let directory = tempfile::tempdir().expect("failed to create temporary directory");
+    write_reads(directory.path());
+    let single_threaded = run(directory.path(), 1, false);
+    assert!(single_threaded.iter().all(|contents| !contents.is_empty()));
+    assert_eq!(run(directory.path(), 4, false), single_threaded);
+}
+
+#[test]
+fn multithreaded_counts_match_single_threaded() {
+    let directory = tempfile::tempdir().expect("failed to create temporary directory");
+    write_reads(directory.path());
+    assert_eq!(run(directory.path(), 4, true), run(directory.path(), 1, true));
+}
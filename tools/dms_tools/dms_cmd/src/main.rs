@@ -12,9 +12,9 @@ struct MainArgs {
 
 #[derive(Parser, Debug)]
 enum Subcommand {
-    /// Extract subsequences from PAF alignments
+    /// Extract subsequences from PAF, SAM or BAM alignments
     Alignment(AlignmentArgs),
-    /// Map coding variants to barcodes in PAF alignments
+    /// Map coding variants to barcodes in PAF, SAM or BAM alignments
     MapCodingVariants(MapCodingVariantsArgs),
 }
 
@@ -31,10 +31,17 @@ fn parse_tuple(s: &str) -> Result<(usize, usize), String> {
 #[derive(Parser, Debug)]
 #[clap(setting = clap::AppSettings::DeriveDisplayOrder)]
 struct AlignmentArgs {
-    /// A PAF alignment file produced by running minimap2 with the `--cs=long` option.
+    /// A PAF alignment file produced by running minimap2 with the `--cs=long` option (or with
+    /// `--cs`, or `-c --eqx`, if `--reference` is given), or a SAM or BAM file (`.sam`, `.sam.gz` or
+    /// `.bam`) with MD tags or a `--reference`.
     #[clap(parse(from_os_str), value_hint=clap::ValueHint::FilePath)]
     source: std::path::PathBuf,
 
+    /// A FASTA file of the sequences the reads were aligned to, needed for PAF alignments with a
+    /// short-form cs tag or only a CIGAR string, and for SAM or BAM alignments without MD tags.
+    #[clap(long, parse(from_os_str), value_hint=clap::ValueHint::FilePath)]
+    reference: Option<std::path::PathBuf>,
+
     /// The starting base (inclusive, zero-indexed) and ending base (exclusive, zero-indexed)
     /// of the desired feature in template space coordinates.
     #[clap(long, value_parser = parse_tuple)]
@@ -48,10 +55,17 @@ struct AlignmentArgs {
 #[derive(Parser, Debug)]
 #[clap(setting = clap::AppSettings::DeriveDisplayOrder)]
 struct MapCodingVariantsArgs {
-    /// A PAF alignment file produced by running minimap2 with the `--cs=long` option.
+    /// A PAF alignment file produced by running minimap2 with the `--cs=long` option (or with
+    /// `--cs`, or `-c --eqx`, if `--reference` is given), or a SAM or BAM file (`.sam`, `.sam.gz` or
+    /// `.bam`) with MD tags or a `--reference`.
     #[clap(parse(from_os_str), value_hint=clap::ValueHint::FilePath)]
     source: std::path::PathBuf,
 
+    /// A FASTA file of the sequences the reads were aligned to, needed for PAF alignments with a
+    /// short-form cs tag or only a CIGAR string, and for SAM or BAM alignments without MD tags.
+    #[clap(long, parse(from_os_str), value_hint=clap::ValueHint::FilePath)]
+    reference: Option<std::path::PathBuf>,
+
     /// The starting base (inclusive, zero-indexed) and ending base (exclusive, zero-indexed)
     /// of the CDS in template space coordinates.
     #[clap(long, value_parser = parse_tuple)]
@@ -73,14 +87,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 fn alignment(arguments: AlignmentArgs) -> Result<(), Box<dyn std::error::Error>> {
-    for maybe_record in alignment::RecordReader::read(&arguments.source)? {
-        if let Ok(record) = maybe_record {
-            if let Some(alignment) = record.alignment_subset(arguments.bounds.0, arguments.bounds.1) {
-                println!("{} {}", alignment.length_relative_to_reference(), alignment.raw_length());
-                if !arguments.exact_length || alignment.length_relative_to_reference() == alignment.raw_length() {
-                    let (_, sequence) = alignment.make_sequences();
-                    println!("{}", sequence);
-                }
+    for record in alignment::open(&arguments.source, arguments.reference.as_deref())? {
+        let record = record?;
+        if let Some(alignment) = record.alignment_subset(arguments.bounds.0, arguments.bounds.1) {
+            println!("{} {}", alignment.length_relative_to_reference(), alignment.raw_length());
+            if !arguments.exact_length || alignment.length_relative_to_reference() == alignment.raw_length() {
+                let (_, sequence) = alignment.make_sequences();
+                println!("{}", sequence);
             }
         }
     }
@@ -89,16 +102,15 @@ fn alignment(arguments: AlignmentArgs) -> Result<(), Box<dyn std::error::Error>>
 }
 
 fn map_coding_variants(arguments: MapCodingVariantsArgs) -> Result<(), Box<dyn std::error::Error>> {
-    for maybe_record in alignment::RecordReader::read(&arguments.source)? {
-        if let Ok(record) = maybe_record {
-            if let (Some(cds), Some(bc)) = (
-                record.alignment_subset(arguments.cds.0, arguments.cds.1),
-                record.alignment_subset(arguments.bc.0, arguments.bc.1)
-            ) {
-                let variants = cds.call_coding_variants();
-            }
+    for record in alignment::open(&arguments.source, arguments.reference.as_deref())? {
+        let record = record?;
+        if let (Some(cds), Some(_bc)) = (
+            record.alignment_subset(arguments.cds.0, arguments.cds.1),
+            record.alignment_subset(arguments.bc.0, arguments.bc.1)
+        ) {
+            let _variants = cds.call_coding_variants();
         }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -11,22 +11,26 @@ fn module(_py: Python, module: &PyModule) -> PyResult<()> {
 }
 
 #[pyclass(name = "RecordReader")]
-#[pyo3(text_signature = "(PAF_file_name, /)")]
+#[pyo3(text_signature = "(alignment_file_name, /, reference_FASTA_file_name=None)")]
 pub struct RecordReaderWrapper {
-    record_reader: alignment::RecordReader
+    record_reader: Box<dyn Iterator<Item = Result<alignment::Record, alignment::Error>> + Send>
 }
 
 #[pymethods]
 impl RecordReaderWrapper {
     #[allow(non_snake_case)]
     #[new]
-    pub fn new(PAF_file_name: &str) -> PyResult<Self> {
-        let PAF_file_path = path::Path::new(PAF_file_name);
+    #[args(reference_FASTA_file_name = "None")]
+    pub fn new(alignment_file_name: &str, reference_FASTA_file_name: Option<String>) -> PyResult<Self> {
+        let alignment_file_path = path::Path::new(alignment_file_name);
+
+        // SAM and BAM files are recognised by their extension
+        let record_reader = alignment::open(alignment_file_path, reference_FASTA_file_name.as_deref().map(path::Path::new)).map_err(|error| {
+            pyo3::exceptions::PyFileNotFoundError::new_err(format!("records file error: {:?}", error))
+        })?;
 
         Ok(RecordReaderWrapper {
-            record_reader: alignment::RecordReader::read(PAF_file_path).map_err(|error| {
-                pyo3::exceptions::PyFileNotFoundError::new_err(format!("records file error: {:?}", error))
-            })?
+            record_reader
         })
     }
 }
@@ -55,7 +59,7 @@ pub struct RecordWrapper {
 impl From<alignment::Record> for RecordWrapper {
     fn from(record: alignment::Record) -> Self {
         Self {
-            record: record
+            record
         }
     }
 }
@@ -72,6 +76,12 @@ impl RecordWrapper {
         Ok(self.record.mapping_quality)
     }
 
+    /// Whether the query aligned to the forward strand of the reference
+    #[getter]
+    pub fn strand_match(&self) -> PyResult<bool> {
+        Ok(self.record.strand() == alignment::Strand::Forward)
+    }
+
     pub fn alignment(&self) -> PyResult<String> {
         Ok(format!("{:?}", self.record.alignment))
     }
@@ -84,7 +94,7 @@ impl RecordWrapper {
                 })
             },
             None => {
-                Err(pyo3::exceptions::PyIndexError::new_err(format!("alignment region not possible for this read")))
+                Err(pyo3::exceptions::PyIndexError::new_err("alignment region not possible for this read"))
             }
         }
     }
@@ -96,7 +106,18 @@ impl RecordWrapper {
                 Ok(query)
             },
             None => {
-                Err(pyo3::exceptions::PyIndexError::new_err(format!("alignment region not possible for this read")))
+                Err(pyo3::exceptions::PyIndexError::new_err("alignment region not possible for this read"))
+            }
+        }
+    }
+
+    /// The (start, end) of the part of the query aligned to a reference region, on the strand the
+    /// query was sequenced from
+    pub fn query_range(&self, start_in_target: usize, end_in_target: usize) -> PyResult<(usize, usize)> {
+        match self.record.query_range(start_in_target, end_in_target) {
+            Some(range) => Ok((range.start, range.end)),
+            None => {
+                Err(pyo3::exceptions::PyIndexError::new_err("alignment region not possible for this read"))
             }
         }
     }
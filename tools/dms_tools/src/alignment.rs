@@ -1,5 +1,14 @@
 use std::{fs, collections::HashMap};
 
+use crate::utils::fasta;
+
+mod cigar;
+mod sam;
+mod tag;
+
+pub use sam::SAMRecordReader;
+pub use tag::TagValue;
+
 #[derive(Debug, Clone)]
 pub struct Record {
     pub query: SequenceRef,
@@ -8,12 +17,16 @@ pub struct Record {
     pub num_matching_bases: usize,
     pub num_mapped_bases: usize,
     pub mapping_quality: u8,
-    pub fields: HashMap<String, String>,
+    /// Optional tags, by name
+    pub fields: HashMap<String, TagValue>,
     pub alignment: Alignment
 }
 
 pub struct RecordReader {
     raw_records_iter: csv::StringRecordsIntoIter<fs::File>,
+    /// Reference sequences by name, needed to rebuild alignments from short-form `cs` tags or
+    /// CIGAR strings
+    reference_sequences: Option<HashMap<String, String>>,
 }
 
 impl RecordReader {
@@ -31,14 +44,21 @@ impl RecordReader {
         };
 
         Ok(Self {
-            raw_records_iter: records_reader.into_records()
+            raw_records_iter: records_reader.into_records(),
+            reference_sequences: None
         })
     }
 
-    fn parse_single_record(raw_record: csv::StringRecord) -> Result<Record, Error> {
-        lazy_static! {
-            static ref ALIGNMENT_MATCHER: regex::Regex = regex::Regex::new(r"(=[ACTGN]+|\*[actgn][actgn]|\+[actgn]+|\-[actgn]+)").expect("failed to compile PAF alignment regex");
-        }
+    /// Use the sequences in a FASTA file as the alignment targets, so that records with a
+    /// short-form `cs` tag (e.g. `:12*ag:5`) or only a CIGAR string (`cg`) with `=` and `X`
+    /// operations (from minimap2's `--eqx`) can be read
+    #[allow(non_snake_case)]
+    pub fn with_reference(mut self, reference_FASTA_file: &std::path::Path) -> Result<Self, Error> {
+        self.reference_sequences = Some(fasta::read_sequences(reference_FASTA_file).map_err(Error::ReadingReference)?);
+        Ok(self)
+    }
+
+    fn parse_single_record(&self, raw_record: csv::StringRecord) -> Result<Record, Error> {
 
         let query = SequenceRef {
             name: raw_record.get(0).ok_or(Error::MissingField("query_name".to_string()))?.to_string(),
@@ -46,7 +66,11 @@ impl RecordReader {
             start: raw_record.get(2).ok_or(Error::MissingField("query_start".to_string()))?.parse::<usize>()?,
             end: raw_record.get(3).ok_or(Error::MissingField("query_end".to_string()))?.parse::<usize>()?
         };
-        let strand_match = raw_record.get(4).ok_or(Error::MissingField("strand".to_string()))? == "+";
+        let strand_match = match raw_record.get(4).ok_or(Error::MissingField("strand".to_string()))? {
+            "+" => true,
+            "-" => false,
+            raw_strand => { return Err(Error::InvalidAlignment(format!("record \"{}\" has strand \"{}\", rather than \"+\" or \"-\"", query.name, raw_strand))); }
+        };
         let reference = SequenceRef {
             name: raw_record.get(5).ok_or(Error::MissingField("target_name".to_string()))?.to_string(),
             length: raw_record.get(6).ok_or(Error::MissingField("target_length".to_string()))?.parse::<usize>()?,
@@ -57,109 +81,222 @@ impl RecordReader {
         let num_mapped_bases = raw_record.get(10).ok_or(Error::MissingField("alignment_length".to_string()))?.parse::<usize>()?;
         let mapping_quality = raw_record.get(11).ok_or(Error::MissingField("mapping_quality".to_string()))?.parse::<u8>()?;
 
-        let mut fields = HashMap::new();
+        let mut fields: HashMap<String, TagValue> = HashMap::new();
         for raw_field in raw_record.iter().skip(12) {
-            let raw_tokens: Vec<&str> = raw_field.splitn(3, ":").collect();
-            fields.insert(raw_tokens[0].to_string(), raw_tokens[2].to_string());
+            let (name, value) = TagValue::parse(raw_field)?;
+            if fields.insert(name.clone(), value).is_some() {
+                return Err(Error::DuplicateTag(name));
+            }
         }
 
-        let alignment: Alignment = fields.get("cs").map_or(vec![], |raw_alignment| {
-            ALIGNMENT_MATCHER.captures_iter(raw_alignment)
-                .filter_map(|capture| capture.get(0))
-                .map(|match_| match_.as_str())
-                .map(|raw| {
-                    let mut characters = raw.chars();
-                    match characters.next().expect("invalid cs tag") {
-                        '=' => { AlignmentOperation::Identical(raw.trim_start_matches("=").to_string()) },
-                        '*' => { AlignmentOperation::Substitution(characters.next().unwrap(), characters.next().unwrap())},
-                        '+' => { AlignmentOperation::Insertion(raw.trim_start_matches("+").to_string()) },
-                        '-' => { AlignmentOperation::Deletion(raw.trim_start_matches("-").to_string()) },
-                        _ => { panic!("invalid cs tag"); }
-                    }
-                })
-            .collect()
-        }).into();
+        let alignment: Alignment = match (fields.get("cs").and_then(TagValue::as_str), fields.get("cg").and_then(TagValue::as_str)) {
+            (Some(raw_alignment), _) => parse_cs(raw_alignment, &reference, self.reference_sequences.as_ref())?,
+            (None, Some(raw_cigar)) => cigar::alignment_operations(&cigar::parse(raw_cigar)?, reference_sequence(self.reference_sequences.as_ref(), &reference)?, None)?,
+            (None, None) => { return Err(Error::MissingAlignment(query.name)); }
+        }.into();
 
         Ok(Record {
-            query: query,
-            reference: reference,
-            strand_match: strand_match,
-            num_matching_bases: num_matching_bases,
-            num_mapped_bases: num_mapped_bases,
-            mapping_quality: mapping_quality,
-            fields: fields,
-            alignment: alignment
+            query,
+            reference,
+            strand_match,
+            num_matching_bases,
+            num_mapped_bases,
+            mapping_quality,
+            fields,
+            alignment
         })
     }
 }
 
+/// Parse a `cs` tag in either the long form (`=ACGT`) or the short form (`:4`), in which runs of
+/// identical bases are rebuilt from the reference sequence, if given
+fn parse_cs(raw_alignment: &str, reference: &SequenceRef, reference_sequences: Option<&HashMap<String, String>>) -> Result<Vec<AlignmentOperation>, Error> {
+    lazy_static! {
+        static ref ALIGNMENT_MATCHER: regex::Regex = regex::Regex::new(r"(=[ACTGN]+|:[0-9]+|\*[actgn][actgn]|\+[actgn]+|\-[actgn]+)").expect("failed to compile PAF alignment regex");
+    }
+
+    let mut operations = Vec::new();
+    let mut position_in_reference = 0;
+    let mut parsed_length = 0;
+    for match_ in ALIGNMENT_MATCHER.find_iter(raw_alignment) {
+        if match_.start() != parsed_length {
+            break;
+        }
+        parsed_length = match_.end();
+
+        let raw = match_.as_str();
+        let mut characters = raw.chars();
+        let operation = match characters.next() {
+            Some('=') => AlignmentOperation::Identical(raw[1..].to_string()),
+            Some(':') => {
+                let length = raw[1..].parse::<usize>()?;
+                let identical = reference_sequence(reference_sequences, reference)?.get(position_in_reference..position_in_reference + length).ok_or_else(|| {
+                    Error::InvalidAlignment(format!("cs tag extends past the end of the alignment to \"{}\"", reference.name))
+                })?;
+                AlignmentOperation::Identical(identical.to_uppercase())
+            },
+            Some('*') => AlignmentOperation::Substitution(characters.next().unwrap_or('n'), characters.next().unwrap_or('n')),
+            Some('+') => AlignmentOperation::Insertion(raw[1..].to_string()),
+            _ => AlignmentOperation::Deletion(raw[1..].to_string()),
+        };
+        position_in_reference += operation.length_relative_to_reference();
+        operations.push(operation);
+    }
+
+    if parsed_length != raw_alignment.len() {
+        return Err(Error::InvalidAlignment(format!("unrecognised cs tag operation at \"{}\"", &raw_alignment[parsed_length..])));
+    }
+    Ok(operations)
+}
+
+/// The part of a reference sequence covered by an alignment
+fn reference_sequence<'a>(reference_sequences: Option<&'a HashMap<String, String>>, reference: &SequenceRef) -> Result<&'a str, Error> {
+    let sequence = reference_sequences.and_then(|sequences| sequences.get(&reference.name)).ok_or_else(|| Error::MissingReference(reference.name.clone()))?;
+    sequence.get(reference.start..reference.end).ok_or_else(|| {
+        Error::InvalidAlignment(format!("alignment to \"{}\" ends at {}, but the reference sequence has length {}", reference.name, reference.end, sequence.len()))
+    })
+}
+
+/// Open an alignment file, read as SAM or BAM if its name ends in `.sam`, `.sam.gz` or `.bam` and
+/// as PAF otherwise, optionally with the reference sequences it was aligned to
+#[allow(non_snake_case)]
+pub fn open(alignment_file: &std::path::Path, reference_FASTA_file: Option<&std::path::Path>) -> Result<Box<dyn Iterator<Item = Result<Record, Error>> + Send>, Error> {
+    let file_name = alignment_file.file_name().map(|file_name| file_name.to_string_lossy().to_ascii_lowercase());
+    if file_name.as_ref().is_some_and(|file_name| [".sam", ".sam.gz", ".bam"].iter().any(|extension| file_name.ends_with(extension))) {
+        let records = SAMRecordReader::read(alignment_file)?;
+        Ok(Box::new(match reference_FASTA_file {
+            Some(reference_FASTA_file) => records.with_reference(reference_FASTA_file)?,
+            None => records,
+        }))
+    } else {
+        let records = RecordReader::read(alignment_file)?;
+        Ok(Box::new(match reference_FASTA_file {
+            Some(reference_FASTA_file) => records.with_reference(reference_FASTA_file)?,
+            None => records,
+        }))
+    }
+}
+
 impl Iterator for RecordReader {
     type Item = Result<Record, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.raw_records_iter.next() {
-            Some(Ok(raw_record)) => {
-                match Self::parse_single_record(raw_record) {
-                    Ok(record) => Some(Ok(record)),
-                    Err(error) => Some(Err(error.into()))
-                }
-            },
-            Some(Err(error)) => {
-                Some(Err(error.into()))
-            },
-            None => None
+        loop {
+            let raw_record = match self.raw_records_iter.next()? {
+                Ok(raw_record) => raw_record,
+                Err(error) => { return Some(Err(error.into())); }
+            };
+            // Unmapped reads (written by minimap2 with --paf-no-hit) have no strand or alignment
+            if raw_record.get(4) == Some("*") {
+                continue;
+            }
+            let line = raw_record.position().map_or(0, csv::Position::line);
+            let name = raw_record.get(0).unwrap_or_default().to_string();
+            return Some(self.parse_single_record(raw_record).map_err(|error| Error::InRecord(line, name, Box::new(error))));
         }
     }
 }
 
 impl Record {
-    pub fn alignment_subset(&self, reference_start: usize, reference_end: usize) -> Option<Alignment> {
-        #[derive(Debug)]
-        enum State {
-            Before,
-            In,
-        }
+    pub fn tag(&self, name: &str) -> Option<&TagValue> {
+        self.fields.get(name)
+    }
+
+    /// The value of an integer (`i`) tag, or `None` if it is missing or has another type
+    pub fn tag_int(&self, name: &str) -> Option<i64> {
+        self.tag(name).and_then(TagValue::as_int)
+    }
+
+    /// The value of a floating-point (`f`) tag, or `None` if it is missing or has another type
+    pub fn tag_float(&self, name: &str) -> Option<f64> {
+        self.tag(name).and_then(TagValue::as_float)
+    }
+
+    /// The value of a string (`Z` or `H`) tag, or `None` if it is missing or has another type
+    pub fn tag_str(&self, name: &str) -> Option<&str> {
+        self.tag(name).and_then(TagValue::as_str)
+    }
+
+    /// Whether this is a primary alignment, according to its `tp:A` tag. Records without one are
+    /// taken to be primary.
+    pub fn is_primary(&self) -> bool {
+        self.tag("tp").and_then(TagValue::as_char).is_none_or(|alignment_type| alignment_type == 'P')
+    }
+
+    /// The strand of the reference the query aligned to
+    pub fn strand(&self) -> Strand {
+        if self.strand_match { Strand::Forward } else { Strand::Reverse }
+    }
 
-        if self.reference.start > reference_start || self.reference.end < reference_end {
+    /// The part of the alignment covering `reference_start..reference_end`, or `None` if the
+    /// alignment does not cover all of it. Whatever the strand, the alignment is in the orientation
+    /// of the reference: for reverse-strand records, the query sequence is the reverse complement of
+    /// the read (see [`Alignment::reverse_complement`]).
+    ///
+    /// Insertions, which fall between two reference bases, are kept with the reference base after
+    /// them: an insertion just before `reference_start` is part of the subset, and one just before
+    /// `reference_end` is not. Insertions at the very end of the alignment, with no reference base
+    /// after them, are kept with the last base instead. Every operation therefore belongs to exactly
+    /// one of any set of adjacent subsets, so concatenating the subsets for `a..b` and `b..c` gives
+    /// the subset for `a..c`. An alignment of only insertions covers no reference bases for them to
+    /// be kept with, so its one subset, the empty range at its position, is empty.
+    pub fn alignment_subset(&self, reference_start: usize, reference_end: usize) -> Option<Alignment> {
+        if self.reference.start > reference_start || self.reference.end < reference_end || reference_start > reference_end {
             return None
         }
 
-        let mut state = State::Before;
         let mut position_in_reference = self.reference.start;
         let mut subset: Vec<AlignmentOperation> = Vec::new();
 
         for alignment_operation in self.alignment.operations().iter() {
-            let operation_length_relative_to_reference = alignment_operation.length_relative_to_reference();
-            position_in_reference += operation_length_relative_to_reference;
-            match state {
-                State::Before => {
-                    if position_in_reference >= reference_start {
-                        if position_in_reference >= reference_end {
-                            let start_offset = alignment_operation.raw_length().saturating_sub(position_in_reference - reference_start);
-                            let stop_offset = alignment_operation.raw_length().saturating_sub(position_in_reference - reference_end);
-                            subset.push(alignment_operation.range_from_to(start_offset, stop_offset));
-                            break;
-                        } else {
-                            let offset = alignment_operation.raw_length().saturating_sub(position_in_reference - reference_start);
-                            subset.push(alignment_operation.range_from(offset));
-                            state = State::In;
-                        }
-                    }
-                },
-                State::In => {
-                    if position_in_reference < reference_end {
-                        subset.push(alignment_operation.clone());
-                    } else {
-                        let offset = alignment_operation.raw_length().saturating_sub(position_in_reference - reference_end);
-                        subset.push(alignment_operation.range_to(offset));
-                        break;
-                    }
-                },
+            let operation_start = position_in_reference;
+            let operation_end = operation_start + alignment_operation.length_relative_to_reference();
+            position_in_reference = operation_end;
+            if operation_start > reference_end {
+                break;
+            }
+
+            if operation_start == operation_end {
+                let is_included = if operation_start == self.reference.end {
+                    reference_start < reference_end && reference_end == self.reference.end
+                } else {
+                    reference_start <= operation_start && operation_start < reference_end
+                };
+                if is_included {
+                    subset.push(alignment_operation.clone());
+                }
+            } else {
+                let start = operation_start.max(reference_start);
+                let stop = operation_end.min(reference_end);
+                if start < stop {
+                    subset.push(alignment_operation.range_from_to(start - operation_start, stop - operation_start));
+                }
             }
         }
 
         Some(subset.into())
     }
+
+    /// The range of the query aligned to `reference_start..reference_end`, in the query's own
+    /// coordinates (i.e. on the strand it was sequenced from, as in `self.query`), or `None` if the
+    /// alignment does not cover all of the reference range. For reverse-strand records, the query
+    /// range runs backwards relative to the reference, so the start of the reference range maps to
+    /// the end of the query range.
+    pub fn query_range(&self, reference_start: usize, reference_end: usize) -> Option<std::ops::Range<usize>> {
+        let before = self.alignment_subset(self.reference.start, reference_start)?.length_relative_to_query();
+        let within = self.alignment_subset(reference_start, reference_end)?.length_relative_to_query();
+        Some(match self.strand() {
+            Strand::Forward => self.query.start + before..self.query.start + before + within,
+            Strand::Reverse => self.query.end - before - within..self.query.end - before,
+        })
+    }
+}
+
+/// The strand of the reference a query aligned to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strand {
+    Forward,
+    Reverse,
 }
 
 #[derive(Clone, Debug)]
@@ -170,7 +307,7 @@ pub struct SequenceRef {
     pub end: usize
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Alignment {
     operations: Vec<AlignmentOperation>
 }
@@ -181,17 +318,24 @@ impl Alignment {
     }
 
     pub fn raw_length(&self) -> usize {
-        self.operations().into_iter().map(|operation| {
+        self.operations().iter().map(|operation| {
             operation.raw_length()
         }).sum()
     }
 
     pub fn length_relative_to_reference(&self) -> usize {
-        self.operations().into_iter().map(|operation| {
+        self.operations().iter().map(|operation| {
             operation.length_relative_to_reference()
         }).sum()
     }
 
+    pub fn length_relative_to_query(&self) -> usize {
+        self.operations().iter().map(|operation| {
+            operation.length_relative_to_query()
+        }).sum()
+    }
+
+    /// The aligned reference and query sequences, in the orientation of the alignment
     pub fn make_sequences(&self) -> (String, String) {
         let mut merged_reference_sequence = String::new();
         let mut merged_query_sequence = String::new();
@@ -202,8 +346,8 @@ impl Alignment {
                     merged_query_sequence.push_str(sequence);
                 },
                 AlignmentOperation::Substitution(reference, query) => {
-                    merged_reference_sequence.push_str(&reference.to_string());
-                    merged_query_sequence.push_str(&query.to_string());
+                    merged_reference_sequence.push(*reference);
+                    merged_query_sequence.push(*query);
                 },
                 AlignmentOperation::Insertion(sequence) => {
                     merged_query_sequence.push_str(sequence);
@@ -216,6 +360,12 @@ impl Alignment {
         (merged_reference_sequence, merged_query_sequence)
     }
 
+    /// The same alignment viewed from the other strand, e.g. to get a reverse-strand record's query
+    /// sequence in the orientation it was read
+    pub fn reverse_complement(&self) -> Self {
+        self.operations().iter().rev().map(AlignmentOperation::reverse_complement).collect::<Vec<_>>().into()
+    }
+
     pub fn call_coding_variants(&self) -> Result<Vec<(char, usize, char)>, Error> {
         lazy_static! {
             static ref TRANSLATION_TABLE: HashMap<&'static str, char> = [
@@ -292,8 +442,8 @@ impl Alignment {
             return Err(Error::InvalidSequenceOperation(format!("cannot call coding variants in sequence region with length that is not a multiple of three (length = {})", reference.len())));
         } else if reference.len() != query.len() {
             return Err(Error::InvalidSequenceOperation(format!("cannot call coding variants for a region with indels (reference length = {}, query length = {})", reference.len(), query.len())));
-        } else if query.len() <= 0 {
-            return Err(Error::InvalidSequenceOperation(format!("cannot call coding variants for a zero-length region")));
+        } else if query.is_empty() {
+            return Err(Error::InvalidSequenceOperation("cannot call coding variants for a zero-length region".to_string()));
         }
 
         let mut variants = Vec::new();
@@ -340,7 +490,7 @@ impl From<Vec<AlignmentOperation>> for Alignment {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum AlignmentOperation {
     Identical(String),
     Substitution(char, char),
@@ -358,6 +508,15 @@ impl AlignmentOperation {
         }
     }
 
+    pub fn length_relative_to_query(&self) -> usize {
+        match self {
+            Self::Identical(sequence) => sequence.len(),
+            Self::Substitution(_, _) => 1,
+            Self::Insertion(sequence) => sequence.len(),
+            Self::Deletion(_) => 0,
+        }
+    }
+
     pub fn raw_length(&self) -> usize {
         match self {
             Self::Identical(sequence) => sequence.len(),
@@ -367,40 +526,94 @@ impl AlignmentOperation {
         }
     }
 
-    pub fn range_to(&self, stop: usize) -> Self {
+    pub fn reverse_complement(&self) -> Self {
         match self {
-            Self::Identical(sequence) => Self::Identical(sequence[..stop].into()),
-            Self::Substitution(reference, query) => Self::Substitution(*reference, *query),
-            Self::Insertion(sequence) => Self::Insertion(sequence[..stop].into()),
-            Self::Deletion(sequence) => Self::Deletion(sequence[..stop].into()),
+            Self::Identical(sequence) => Self::Identical(reverse_complement(sequence)),
+            Self::Substitution(reference, query) => Self::Substitution(complement(*reference), complement(*query)),
+            Self::Insertion(sequence) => Self::Insertion(reverse_complement(sequence)),
+            Self::Deletion(sequence) => Self::Deletion(reverse_complement(sequence)),
         }
     }
 
+    /// The part of the operation before an offset into its sequence
+    ///
+    /// # Panics
+    ///
+    /// As for [`AlignmentOperation::range_from_to`]
+    pub fn range_to(&self, stop: usize) -> Self {
+        self.range_from_to(0, stop)
+    }
+
+    /// The part of the operation from an offset into its sequence
+    ///
+    /// # Panics
+    ///
+    /// As for [`AlignmentOperation::range_from_to`]
     pub fn range_from(&self, start: usize) -> Self {
-        match self {
-            Self::Identical(sequence) => Self::Identical(sequence[start..].into()),
-            Self::Substitution(reference, query) => Self::Substitution(*reference, *query),
-            Self::Insertion(sequence) => Self::Insertion(sequence[start..].into()),
-            Self::Deletion(sequence) => Self::Deletion(sequence[start..].into()),
-        }
+        self.range_from_to(start, self.raw_length())
     }
 
+    /// The part of the operation between two offsets into its sequence
+    ///
+    /// # Panics
+    ///
+    /// If the range is out of bounds or reversed, as when slicing a string. As substitutions are a
+    /// single base, they can only be taken whole (`0..1`), and any other range panics.
     pub fn range_from_to(&self, start: usize, stop: usize) -> Self {
         match self {
             Self::Identical(sequence) => Self::Identical(sequence[start..stop].into()),
-            Self::Substitution(reference, query) => Self::Substitution(*reference, *query),
+            Self::Substitution(reference, query) => {
+                assert!(start == 0 && stop == 1, "range {}..{} is not the whole of a substitution", start, stop);
+                Self::Substitution(*reference, *query)
+            },
             Self::Insertion(sequence) => Self::Insertion(sequence[start..stop].into()),
             Self::Deletion(sequence) => Self::Deletion(sequence[start..stop].into()),
         }
     }
 }
 
+/// The complement of a base, keeping its case. Anything other than A, C, G or T (e.g. N) is its
+/// own complement.
+fn complement(base: char) -> char {
+    match base {
+        'A' => 'T',
+        'C' => 'G',
+        'G' => 'C',
+        'T' => 'A',
+        'a' => 't',
+        'c' => 'g',
+        'g' => 'c',
+        't' => 'a',
+        _ => base,
+    }
+}
+
+fn reverse_complement(sequence: &str) -> String {
+    sequence.chars().rev().map(complement).collect()
+}
+
 #[derive(Debug)]
 pub enum Error {
     Reading(csv::Error),
     Parsing,
     MissingField(String),
-    InvalidSequenceOperation(String)
+    InvalidSequenceOperation(String),
+    /// A record has neither a `cs` tag nor a CIGAR string
+    MissingAlignment(String),
+    /// A record needs a reference sequence that was not provided
+    MissingReference(String),
+    InvalidAlignment(String),
+    /// An optional tag is not written as `TAG:TYPE:VALUE` with a valid type and value
+    InvalidTag(String),
+    /// A record has more than one optional tag with the same name
+    DuplicateTag(String),
+    ReadingReference(std::io::Error),
+    ReadingSAM(std::io::Error),
+    /// An error in the record with the given query name, on the given line of a PAF or SAM file
+    InRecord(u64, String, Box<Error>),
+    /// An error in the record with the given query name, at the given (one-based) position in a
+    /// BAM file
+    InBAMRecord(u64, String, Box<Error>)
 }
 
 impl std::error::Error for Error {
@@ -413,7 +626,16 @@ impl std::fmt::Display for Error {
             Self::Reading(error) => write!(f, "input error: {}", error),
             Self::Parsing => write!(f, "failed to parse input"),
             Self::MissingField(name) => write!(f, "expected field \"{}\"", name),
-            Self::InvalidSequenceOperation(detail) => write!(f, "invalid sequence: {}", detail)
+            Self::InvalidSequenceOperation(detail) => write!(f, "invalid sequence: {}", detail),
+            Self::MissingAlignment(name) => write!(f, "record \"{}\" has neither a cs tag nor a CIGAR string (cg tag); run minimap2 with --cs, or -c --eqx", name),
+            Self::MissingReference(name) => write!(f, "reference sequence \"{}\" is needed to rebuild the alignment but was not provided", name),
+            Self::InvalidAlignment(detail) => write!(f, "invalid alignment: {}", detail),
+            Self::InvalidTag(raw_field) => write!(f, "invalid optional tag \"{}\"", raw_field),
+            Self::DuplicateTag(name) => write!(f, "optional tag \"{}\" is given more than once", name),
+            Self::ReadingReference(error) => write!(f, "reference input error: {}", error),
+            Self::ReadingSAM(error) => write!(f, "SAM/BAM input error: {}", error),
+            Self::InRecord(line, name, error) => write!(f, "line {}, record \"{}\": {}", line, name, error),
+            Self::InBAMRecord(number, name, error) => write!(f, "BAM record {}, \"{}\": {}", number, name, error)
         }
     }
 }
@@ -429,3 +651,240 @@ impl From<std::num::ParseIntError> for Error {
         Self::Parsing
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A read of the reference ATG AAA CCC GGG TTT (M K P G F) with the second codon mutated to AGA
+    /// (K2R), and three and two flanking bases that do not align, sequenced from the reverse strand
+    const REVERSE_READ: &str = "GGGAAACCCGGGTCTCATCC";
+
+    /// Read every record from the given PAF lines
+    fn read_paf_lines(lines: &[&str]) -> Vec<Result<Record, Error>> {
+        let path = std::env::temp_dir().join(format!("dms_tools_alignment_{}_{:?}.paf", std::process::id(), std::thread::current().id()));
+        fs::write(&path, lines.iter().map(|line| format!("{}\n", line)).collect::<String>()).expect("failed to write test PAF file");
+        let records = RecordReader::read(&path).expect("failed to open test PAF file").collect();
+        fs::remove_file(&path).ok();
+        records
+    }
+
+    fn read_paf(line: &str) -> Result<Record, Error> {
+        read_paf_lines(&[line]).pop().expect("no record in test PAF file")
+    }
+
+    fn record(strand: &str) -> Record {
+        read_paf(&format!("read\t20\t3\t18\t{}\tamp\t15\t0\t15\t14\t15\t60\tcs:Z:=ATGA*ag=ACCCGGGTTT", strand)).expect("failed to parse test record")
+    }
+
+    #[test]
+    fn strand_is_parsed() {
+        assert_eq!(record("+").strand(), Strand::Forward);
+        assert_eq!(record("-").strand(), Strand::Reverse);
+        assert!(matches!(read_paf("read\t20\t3\t18\t?\tamp\t15\t0\t15\t14\t15\t60\tcs:Z:=ATGA*ag=ACCCGGGTTT"), Err(Error::InRecord(1, _, error)) if matches!(*error, Error::InvalidAlignment(_))));
+    }
+
+    #[test]
+    fn tags_are_read_by_type() {
+        let secondary = read_paf("read\t20\t3\t18\t+\tamp\t15\t0\t15\t14\t15\t60\tNM:i:1\tde:f:0.07\ttp:A:S\tcs:Z:=ATGA*ag=ACCCGGGTTT").expect("valid record");
+        assert_eq!(secondary.tag_int("NM"), Some(1));
+        assert_eq!(secondary.tag_float("de"), Some(0.07));
+        assert_eq!(secondary.tag_str("cs"), Some("=ATGA*ag=ACCCGGGTTT"));
+        assert!(!secondary.is_primary());
+        // Missing tags, and tags of another type, have no value
+        assert_eq!(secondary.tag_int("de"), None);
+        assert_eq!(secondary.tag_float("NM"), None);
+        assert_eq!(secondary.tag_int("AS"), None);
+        assert!(record("+").is_primary());
+        let wrongly_typed = read_paf("read\t20\t3\t18\t+\tamp\t15\t0\t15\t14\t15\t60\ttp:Z:S\tcs:Z:=ATGA*ag=ACCCGGGTTT").expect("valid record");
+        assert!(wrongly_typed.is_primary());
+    }
+
+    #[test]
+    fn duplicate_tags_are_rejected() {
+        let record = read_paf("read\t20\t3\t18\t+\tamp\t15\t0\t15\t14\t15\t60\tNM:i:1\tNM:i:2\tcs:Z:=ATGA*ag=ACCCGGGTTT");
+        assert!(matches!(record, Err(Error::InRecord(1, _, error)) if matches!(*error, Error::DuplicateTag(ref name) if name == "NM")));
+    }
+
+    #[test]
+    fn unmapped_records_are_skipped() {
+        let records = read_paf_lines(&["unmapped\t20\t0\t0\t*\t*\t0\t0\t0\t0\t0\t0\trl:i:0", "read\t20\t3\t18\t+\tamp\t15\t0\t15\t14\t15\t60\tcs:Z:=ATGA*ag=ACCCGGGTTT"]);
+        assert_eq!(records.len(), 1);
+        assert!(matches!(&records[0], Ok(record) if record.query.name == "read"));
+    }
+
+    #[test]
+    fn errors_give_the_line_and_record() {
+        let records = read_paf_lines(&["read1\t20\t3\t18\t+\tamp\t15\t0\t15\t14\t15\t60\tcs:Z:=ATGA*ag=ACCCGGGTTT", "read2\t20\tx\t18\t+\tamp\t15\t0\t15\t14\t15\t60\tcs:Z:=ATGA*ag=ACCCGGGTTT"]);
+        assert!(matches!(&records[1], Err(Error::InRecord(2, name, error)) if name == "read2" && matches!(**error, Error::Parsing)));
+        assert_eq!(records[1].as_ref().err().map(ToString::to_string), Some("line 2, record \"read2\": failed to parse input".to_string()));
+    }
+
+    #[test]
+    fn short_cs_tags_are_rebuilt_from_the_reference() {
+        let reference_sequences = HashMap::from([("amp".to_string(), "ttATGAAACCCGGGTTT".to_string())]);
+        let reference = SequenceRef { name: "amp".to_string(), length: 17, start: 2, end: 17 };
+        let operations = parse_cs(":4*ag:3-cg:1+t:4", &reference, Some(&reference_sequences)).expect("valid cs tag");
+        assert_eq!(Alignment::from(operations).make_sequences(), ("ATGAaACCcgGGTTT".to_string(), "ATGAgACCGtGTTT".to_string()));
+        assert!(matches!(parse_cs(":4", &reference, None), Err(Error::MissingReference(_))));
+        assert!(matches!(parse_cs(":16", &reference, Some(&reference_sequences)), Err(Error::InvalidAlignment(_))));
+    }
+
+    #[test]
+    fn unrecognised_cs_operations_are_rejected() {
+        let reference = SequenceRef { name: "amp".to_string(), length: 4, start: 0, end: 4 };
+        assert!(matches!(parse_cs("=ACGT~ac", &reference, None), Err(Error::InvalidAlignment(detail)) if detail.contains("~ac")));
+        assert!(matches!(parse_cs("=AC*gg=T", &reference, None), Ok(operations) if operations.len() == 3));
+    }
+
+    #[test]
+    fn reverse_strand_subsets_are_in_reference_orientation() {
+        let forward = record("+");
+        let reverse = record("-");
+        for record in [&forward, &reverse] {
+            let subset = record.alignment_subset(0, 15).expect("alignment covers the reference");
+            assert_eq!(subset.make_sequences(), ("ATGAaACCCGGGTTT".to_string(), "ATGAgACCCGGGTTT".to_string()));
+            assert_eq!(subset.call_coding_variants().expect("alignment has no indels"), vec![('K', 2, 'R')]);
+        }
+        assert_eq!(reverse.alignment_subset(3, 6), forward.alignment_subset(3, 6));
+    }
+
+    #[test]
+    fn query_ranges_follow_the_strand() {
+        assert_eq!(record("+").query_range(3, 6), Some(6..9));
+        assert_eq!(record("-").query_range(3, 6), Some(12..15));
+        assert_eq!(record("-").query_range(0, 15), Some(3..18));
+        assert_eq!(record("-").query_range(0, 16), None);
+    }
+
+    #[test]
+    fn reverse_complement_gives_the_read_orientation() {
+        let reverse = record("-");
+        let query_range = reverse.query_range(3, 6).expect("alignment covers the codon");
+        let (_, query) = reverse.alignment_subset(3, 6).expect("alignment covers the codon").reverse_complement().make_sequences();
+        assert_eq!(query.to_uppercase(), REVERSE_READ[query_range]);
+    }
+
+    /// A forward-strand record with the given alignment, starting at `reference_start`
+    fn record_with_operations(reference_start: usize, operations: Vec<AlignmentOperation>) -> Record {
+        let alignment: Alignment = operations.into();
+        let reference_end = reference_start + alignment.length_relative_to_reference();
+        let query_length = alignment.length_relative_to_query();
+        Record {
+            query: SequenceRef { name: "read".to_string(), length: query_length, start: 0, end: query_length },
+            reference: SequenceRef { name: "amp".to_string(), length: reference_end, start: reference_start, end: reference_end },
+            strand_match: true,
+            num_matching_bases: 0,
+            num_mapped_bases: alignment.raw_length(),
+            mapping_quality: 60,
+            fields: HashMap::new(),
+            alignment
+        }
+    }
+
+    /// Merge adjacent runs of identical bases, insertions and deletions, which subsetting may split
+    fn normalised(alignment: &Alignment) -> Vec<AlignmentOperation> {
+        let mut operations: Vec<AlignmentOperation> = Vec::new();
+        for operation in alignment.operations() {
+            match (operations.last_mut(), operation) {
+                (_, operation) if operation.raw_length() == 0 => {},
+                (Some(AlignmentOperation::Identical(previous)), AlignmentOperation::Identical(sequence))
+                | (Some(AlignmentOperation::Insertion(previous)), AlignmentOperation::Insertion(sequence))
+                | (Some(AlignmentOperation::Deletion(previous)), AlignmentOperation::Deletion(sequence)) => previous.push_str(sequence),
+                _ => operations.push(operation.clone()),
+            }
+        }
+        operations
+    }
+
+    #[test]
+    fn insertions_belong_to_the_following_base() {
+        use AlignmentOperation::*;
+        let record = record_with_operations(10, vec![Insertion("a".into()), Identical("ACG".into()), Insertion("c".into()), Substitution('t', 'g'), Deletion("ac".into()), Insertion("g".into())]);
+        assert_eq!(record.alignment_subset(10, 13).map(|subset| normalised(&subset)), Some(vec![Insertion("a".into()), Identical("ACG".into())]));
+        assert_eq!(record.alignment_subset(11, 14).map(|subset| normalised(&subset)), Some(vec![Identical("CG".into()), Insertion("c".into()), Substitution('t', 'g')]));
+        assert_eq!(record.alignment_subset(13, 13).map(|subset| normalised(&subset)), Some(vec![]));
+        assert_eq!(record.alignment_subset(15, 16).map(|subset| normalised(&subset)), Some(vec![Deletion("c".into()), Insertion("g".into())]));
+        assert_eq!(record.alignment_subset(16, 16).map(|subset| normalised(&subset)), Some(vec![]));
+        assert!(record.alignment_subset(9, 12).is_none());
+        assert!(record.alignment_subset(12, 17).is_none());
+        assert!(record.alignment_subset(12, 11).is_none());
+    }
+
+    #[test]
+    fn insertion_only_alignments_have_empty_subsets() {
+        let record = record_with_operations(4, vec![AlignmentOperation::Insertion("ac".into())]);
+        assert_eq!(record.alignment_subset(4, 4).map(|subset| normalised(&subset)), Some(vec![]));
+        assert_eq!(record.query_range(4, 4), Some(0..0));
+        assert!(record.alignment_subset(3, 4).is_none());
+        assert!(record.alignment_subset(4, 5).is_none());
+    }
+
+    #[test]
+    #[should_panic]
+    fn substitutions_cannot_be_split() {
+        AlignmentOperation::Substitution('a', 'c').range_from_to(1, 1);
+    }
+
+    mod properties {
+        use proptest::prelude::*;
+
+        use super::*;
+
+        fn bases(length: std::ops::Range<usize>) -> impl Strategy<Value = String> {
+            proptest::collection::vec(proptest::sample::select(vec!['a', 'c', 'g', 't']), length).prop_map(|bases| bases.into_iter().collect())
+        }
+
+        fn operation() -> impl Strategy<Value = AlignmentOperation> {
+            prop_oneof![
+                bases(1..6).prop_map(|sequence| AlignmentOperation::Identical(sequence.to_uppercase())),
+                (bases(1..2), bases(1..2)).prop_map(|(reference, query)| AlignmentOperation::Substitution(reference.chars().next().unwrap_or('a'), query.chars().next().unwrap_or('c'))),
+                bases(1..4).prop_map(AlignmentOperation::Insertion),
+                bases(1..4).prop_map(AlignmentOperation::Deletion),
+            ]
+        }
+
+        /// A record and a sorted set of positions that split its reference range into adjacent
+        /// (possibly empty) subsets. Alignments cover at least one reference base, so that
+        /// insertions have a base to belong to.
+        fn record_and_boundaries() -> impl Strategy<Value = (Record, Vec<usize>)> {
+            let operations = proptest::collection::vec(operation(), 1..12).prop_filter("alignment covers no reference bases", |operations| {
+                operations.iter().any(|operation| operation.length_relative_to_reference() > 0)
+            });
+            (0..20usize, operations).prop_flat_map(|(reference_start, operations)| {
+                let record = record_with_operations(reference_start, operations);
+                let boundaries = proptest::collection::vec(record.reference.start..=record.reference.end, 0..6);
+                (Just(record), boundaries).prop_map(|(record, mut boundaries)| {
+                    boundaries.push(record.reference.start);
+                    boundaries.push(record.reference.end);
+                    boundaries.sort_unstable();
+                    (record, boundaries)
+                })
+            })
+        }
+
+        proptest! {
+            #[test]
+            fn adjacent_subsets_concatenate_to_the_whole_alignment((record, boundaries) in record_and_boundaries()) {
+                let mut operations = Vec::new();
+                for window in boundaries.windows(2) {
+                    let subset = record.alignment_subset(window[0], window[1]).expect("subset within the alignment");
+                    prop_assert_eq!(subset.length_relative_to_reference(), window[1] - window[0]);
+                    operations.extend(subset.operations().iter().cloned());
+                }
+                prop_assert_eq!(normalised(&operations.into()), normalised(&record.alignment));
+            }
+
+            #[test]
+            fn adjacent_query_ranges_tile_the_aligned_query((record, boundaries) in record_and_boundaries()) {
+                let mut query_position = record.query.start;
+                for window in boundaries.windows(2) {
+                    let query_range = record.query_range(window[0], window[1]).expect("subset within the alignment");
+                    prop_assert_eq!(query_range.start, query_position);
+                    query_position = query_range.end;
+                }
+                prop_assert_eq!(query_position, record.query.end);
+            }
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use super::{AlignmentOperation, Error};
+
+/// Split a CIGAR string (e.g. `10M2I5M`) into (length, operation) pairs
+pub fn parse(raw_cigar: &str) -> Result<Vec<(usize, char)>, Error> {
+    let invalid = || Error::InvalidAlignment(format!("invalid CIGAR string \"{}\"", raw_cigar));
+
+    let mut operations = Vec::new();
+    let mut length_start = 0;
+    for (index, code) in raw_cigar.char_indices() {
+        if code.is_ascii_digit() {
+            continue;
+        }
+        let length = raw_cigar[length_start..index].parse::<usize>().map_err(|_| invalid())?;
+        operations.push((length, code));
+        length_start = index + code.len_utf8();
+    }
+    if length_start != raw_cigar.len() {
+        return Err(invalid());
+    }
+    Ok(operations)
+}
+
+/// Rebuild the alignment operations described by a CIGAR string. `reference` is the part of the
+/// reference covered by the alignment, and `query` the whole query sequence (including any soft
+/// clipped bases), if known. Without the query, only `=` operations can be rebuilt: `M` and `X`
+/// operations are an error, as they do not say which bases differ from the reference or how, and
+/// inserted bases are given as `n`.
+pub fn alignment_operations(cigar: &[(usize, char)], reference: &str, query: Option<&str>) -> Result<Vec<AlignmentOperation>, Error> {
+    let reference = reference.as_bytes();
+    let query = query.map(str::as_bytes);
+    let reference_bases = |start: usize, length: usize| {
+        reference.get(start..start + length).ok_or_else(|| Error::InvalidAlignment("CIGAR string extends past the end of the reference sequence".to_string()))
+    };
+    let query_bases = |start: usize, length: usize| {
+        query.map(|query| query.get(start..start + length).ok_or_else(|| Error::InvalidAlignment("CIGAR string extends past the end of the query sequence".to_string()))).transpose()
+    };
+
+    let mut operations: Vec<AlignmentOperation> = Vec::new();
+    let mut position_in_reference = 0;
+    let mut position_in_query = 0;
+    for &(length, code) in cigar {
+        match code {
+            'M' | '=' | 'X' => {
+                let reference_bases = reference_bases(position_in_reference, length)?;
+                let query_bases = query_bases(position_in_query, length)?;
+                if query_bases.is_none() && code != '=' {
+                    return Err(Error::InvalidAlignment(format!("CIGAR operation {} needs the query sequence; run minimap2 with --cs, or with --eqx for CIGAR strings", code)));
+                }
+                for (index, reference_base) in reference_bases.iter().enumerate() {
+                    let reference_base = char::from(reference_base.to_ascii_uppercase());
+                    let query_base = match query_bases {
+                        Some(query_bases) => char::from(query_bases[index].to_ascii_uppercase()),
+                        None => reference_base,
+                    };
+                    if query_base == reference_base {
+                        push_identical(&mut operations, reference_base);
+                    } else {
+                        operations.push(AlignmentOperation::Substitution(reference_base.to_ascii_lowercase(), query_base.to_ascii_lowercase()));
+                    }
+                }
+                position_in_reference += length;
+                position_in_query += length;
+            },
+            'I' => {
+                let sequence = match query_bases(position_in_query, length)? {
+                    Some(query_bases) => String::from_utf8_lossy(query_bases).to_ascii_lowercase(),
+                    None => "n".repeat(length),
+                };
+                operations.push(AlignmentOperation::Insertion(sequence));
+                position_in_query += length;
+            },
+            'D' => {
+                operations.push(AlignmentOperation::Deletion(String::from_utf8_lossy(reference_bases(position_in_reference, length)?).to_ascii_lowercase()));
+                position_in_reference += length;
+            },
+            'S' => { position_in_query += length; },
+            'H' | 'P' => {},
+            'N' => { return Err(Error::InvalidAlignment("spliced alignments (CIGAR operation N) are not supported".to_string())); },
+            _ => { return Err(Error::InvalidAlignment(format!("unknown CIGAR operation \"{}\"", code))); },
+        }
+    }
+    Ok(operations)
+}
+
+/// Rebuild the part of the reference covered by an alignment from the whole query sequence, the
+/// CIGAR string and the MD tag, which gives the reference bases at mismatches and deletions
+pub fn reference_from_md(cigar: &[(usize, char)], md: &str, query: &str) -> Result<String, Error> {
+    let invalid = |detail: &str| Error::InvalidAlignment(format!("MD tag \"{}\" {}", md, detail));
+    let md_bytes = md.as_bytes();
+    let query = query.as_bytes();
+
+    let mut reference = String::new();
+    let mut md_position = 0;
+    let mut matches_remaining = 0;
+    let mut position_in_query = 0;
+    for &(length, code) in cigar {
+        match code {
+            'M' | '=' | 'X' => {
+                for _ in 0..length {
+                    if matches_remaining == 0 {
+                        matches_remaining = next_match_run(md_bytes, &mut md_position);
+                    }
+                    if matches_remaining > 0 {
+                        let query_base = query.get(position_in_query).ok_or_else(|| invalid("extends past the end of the query sequence"))?;
+                        reference.push(char::from(*query_base));
+                        matches_remaining -= 1;
+                    } else {
+                        match md_bytes.get(md_position) {
+                            Some(base) if base.is_ascii_alphabetic() => { reference.push(char::from(*base)); },
+                            _ => { return Err(invalid("does not describe the CIGAR string's aligned bases")); }
+                        }
+                        md_position += 1;
+                    }
+                    position_in_query += 1;
+                }
+            },
+            'D' => {
+                if matches_remaining > 0 || next_match_run(md_bytes, &mut md_position) > 0 || md_bytes.get(md_position) != Some(&b'^') {
+                    return Err(invalid("does not describe the CIGAR string's deletions"));
+                }
+                let deleted = md_bytes.get(md_position + 1..md_position + 1 + length).filter(|deleted| deleted.iter().all(u8::is_ascii_alphabetic)).ok_or_else(|| invalid("does not describe the CIGAR string's deletions"))?;
+                reference.push_str(&String::from_utf8_lossy(deleted));
+                md_position += 1 + length;
+            },
+            'I' | 'S' => { position_in_query += length; },
+            _ => {},
+        }
+    }
+    if matches_remaining > 0 || next_match_run(md_bytes, &mut md_position) > 0 || md_position != md_bytes.len() {
+        return Err(invalid("describes more bases than the CIGAR string"));
+    }
+    Ok(reference)
+}
+
+/// Read the length of the run of matching bases at `position` in an MD tag, if there is one,
+/// skipping runs of zero length (which separate mismatches and deletions)
+fn next_match_run(md: &[u8], position: &mut usize) -> usize {
+    while md.get(*position).is_some_and(u8::is_ascii_digit) {
+        let run_end = md[*position..].iter().position(|character| !character.is_ascii_digit()).map_or(md.len(), |offset| *position + offset);
+        let run_length = std::str::from_utf8(&md[*position..run_end]).ok().and_then(|run| run.parse::<usize>().ok()).unwrap_or(0);
+        *position = run_end;
+        if run_length > 0 {
+            return run_length;
+        }
+    }
+    0
+}
+
+/// Add a base to the run of identical bases at the end of `operations`, starting one if needed
+fn push_identical(operations: &mut Vec<AlignmentOperation>, base: char) {
+    match operations.last_mut() {
+        Some(AlignmentOperation::Identical(sequence)) => sequence.push(base),
+        _ => operations.push(AlignmentOperation::Identical(base.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cigar_strings_are_split_into_operations() {
+        assert_eq!(parse("3S10M2I5=1X4D").expect("valid CIGAR string"), vec![(3, 'S'), (10, 'M'), (2, 'I'), (5, '='), (1, 'X'), (4, 'D')]);
+        assert_eq!(parse("").expect("valid CIGAR string"), vec![]);
+        assert!(matches!(parse("10M5"), Err(Error::InvalidAlignment(_))));
+        assert!(matches!(parse("M10"), Err(Error::InvalidAlignment(_))));
+    }
+
+    #[test]
+    fn operations_use_the_query_when_known() {
+        use AlignmentOperation::*;
+        let cigar = parse("2S3M1I2M2D2M").expect("valid CIGAR string");
+        let operations = alignment_operations(&cigar, "ACGTAGGTT", Some("nnACTtTAtt")).expect("valid alignment");
+        assert_eq!(operations, vec![Identical("AC".into()), Substitution('g', 't'), Insertion("t".into()), Identical("TA".into()), Deletion("gg".into()), Identical("TT".into())]);
+    }
+
+    #[test]
+    fn operations_without_the_query_need_explicit_matches() {
+        use AlignmentOperation::*;
+        let cigar = parse("2=1I1=1D").expect("valid CIGAR string");
+        assert_eq!(alignment_operations(&cigar, "ACGT", None).expect("valid alignment"), vec![Identical("AC".into()), Insertion("n".into()), Identical("G".into()), Deletion("t".into())]);
+        assert!(matches!(alignment_operations(&cigar, "ACG", None), Err(Error::InvalidAlignment(_))));
+        assert!(matches!(alignment_operations(&[(4, 'M')], "ACGT", None), Err(Error::InvalidAlignment(_))));
+        assert!(matches!(alignment_operations(&[(2, '='), (1, 'X'), (1, '=')], "ACGT", None), Err(Error::InvalidAlignment(_))));
+        assert!(matches!(alignment_operations(&[(3, 'M')], "ACGT", Some("AC")), Err(Error::InvalidAlignment(_))));
+        assert!(matches!(alignment_operations(&[(1, 'M'), (5, 'N'), (1, 'M')], "ACGTACG", None), Err(Error::InvalidAlignment(_))));
+    }
+
+    #[test]
+    fn md_tags_give_mismatched_and_deleted_bases() {
+        // Runs of zero length separate adjacent mismatches, and a mismatch following a deletion
+        assert_eq!(reference_from_md(&parse("6M").expect("valid CIGAR string"), "2A0C2", "ACGTAC").expect("valid MD tag"), "ACACAC");
+        assert_eq!(reference_from_md(&parse("3M2D2M").expect("valid CIGAR string"), "3^AC0T1", "ACGGT").expect("valid MD tag"), "ACGACTT");
+        assert_eq!(reference_from_md(&parse("3M1D1M").expect("valid CIGAR string"), "3^A1", "ACGT").expect("valid MD tag"), "ACGAT");
+    }
+
+    #[test]
+    fn md_tags_skip_clipped_and_inserted_bases() {
+        assert_eq!(reference_from_md(&parse("2H2S4M1S").expect("valid CIGAR string"), "4", "GGACGTC").expect("valid MD tag"), "ACGT");
+        assert_eq!(reference_from_md(&parse("2M1I2M").expect("valid CIGAR string"), "4", "ACTGT").expect("valid MD tag"), "ACGT");
+        assert_eq!(reference_from_md(&parse("2=1X2=").expect("valid CIGAR string"), "2G2", "ACTTA").expect("valid MD tag"), "ACGTA");
+    }
+
+    #[test]
+    fn md_tags_must_agree_with_the_cigar_string() {
+        let cigar = parse("2M1D2M").expect("valid CIGAR string");
+        assert!(matches!(reference_from_md(&cigar, "4", "ACGT"), Err(Error::InvalidAlignment(_))));
+        assert!(matches!(reference_from_md(&cigar, "2^AG2", "ACGT"), Err(Error::InvalidAlignment(_))));
+        assert!(matches!(reference_from_md(&parse("4M").expect("valid CIGAR string"), "3", "ACGT"), Err(Error::InvalidAlignment(_))));
+        assert!(matches!(reference_from_md(&parse("4M").expect("valid CIGAR string"), "5", "ACGT"), Err(Error::InvalidAlignment(_))));
+        assert!(matches!(reference_from_md(&parse("4M").expect("valid CIGAR string"), "2^A2", "ACGT"), Err(Error::InvalidAlignment(_))));
+        assert!(matches!(reference_from_md(&parse("4M").expect("valid CIGAR string"), "4", "ACG"), Err(Error::InvalidAlignment(_))));
+    }
+}
@@ -0,0 +1,289 @@
+use std::{collections::HashMap, fs, io::{self, prelude::*}};
+
+use noodles::{bam, bgzf, sam};
+use noodles::sam::alignment::{RecordBuf, record::cigar::op::Kind, record_buf::data::field::{Value, value::Array}};
+
+use super::{cigar, reference_sequence, Alignment, AlignmentOperation, Error, Record, SequenceRef, TagValue};
+use crate::utils::fasta;
+
+enum Source {
+    Sam(sam::io::Reader<LineCounter<Box<dyn BufRead + Send>>>),
+    /// A BAM reader and the number of records read from it so far
+    Bam(bam::io::Reader<bgzf::io::Reader<io::BufReader<fs::File>>>, u64),
+}
+
+/// Counts the lines read through it, so that errors can give the line of the SAM record
+struct LineCounter<R> {
+    inner: R,
+    lines: u64,
+}
+
+impl<R: BufRead> Read for LineCounter<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let length = self.inner.read(buffer)?;
+        self.lines += buffer[..length].iter().filter(|byte| **byte == b'\n').count() as u64;
+        Ok(length)
+    }
+}
+
+impl<R: BufRead> BufRead for LineCounter<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        // The consumed bytes are still at the start of the inner reader's buffer
+        if let Ok(buffer) = self.inner.fill_buf() {
+            self.lines += buffer[..amount.min(buffer.len())].iter().filter(|byte| **byte == b'\n').count() as u64;
+        }
+        self.inner.consume(amount);
+    }
+}
+
+/// Reads alignments from a SAM or BAM file (e.g. from pbmm2, or minimap2 with `-a`) as the same
+/// [`Record`]s as PAF. Alignments are rebuilt from the reference sequences if they are given, and
+/// otherwise from each record's CIGAR string and MD tag. Only primary alignments with a sequence are
+/// read: unmapped reads, secondary and supplementary alignments, and records whose sequence is
+/// omitted (`*`, as minimap2 writes for secondary alignments) are skipped.
+pub struct SAMRecordReader {
+    source: Source,
+    header: sam::Header,
+    record: RecordBuf,
+    reference_sequences: Option<HashMap<String, String>>,
+}
+
+impl SAMRecordReader {
+    /// Open a SAM or BAM file, telling them apart by whether the decompressed contents start with
+    /// BAM's magic number. SAM files may be compressed with gzip or bgzip.
+    #[allow(non_snake_case)]
+    pub fn read(SAM_file: &std::path::Path) -> Result<Self, Error> {
+        let open = || Ok(io::BufReader::new(fs::File::open(SAM_file)?));
+        let mut source = open().map_err(Error::ReadingSAM)?;
+        let is_compressed = source.fill_buf().map_err(Error::ReadingSAM)?.starts_with(&[0x1f, 0x8b]);
+        let mut source: Box<dyn BufRead + Send> = if is_compressed {
+            Box::new(io::BufReader::new(flate2::bufread::MultiGzDecoder::new(source)))
+        } else {
+            Box::new(source)
+        };
+        let is_bam = source.fill_buf().map_err(Error::ReadingSAM)?.starts_with(b"BAM\x01");
+        let (source, header) = if is_bam {
+            // BAM is read from the start again, as its own BGZF reader
+            let mut reader = bam::io::Reader::new(open().map_err(Error::ReadingSAM)?);
+            let header = reader.read_header().map_err(Error::ReadingSAM)?;
+            (Source::Bam(reader, 0), header)
+        } else {
+            let mut reader = sam::io::Reader::new(LineCounter { inner: source, lines: 0 });
+            let header = reader.read_header().map_err(Error::ReadingSAM)?;
+            (Source::Sam(reader), header)
+        };
+
+        Ok(Self {
+            source,
+            header,
+            record: RecordBuf::default(),
+            reference_sequences: None
+        })
+    }
+
+    /// Use the sequences in a FASTA file as the alignment targets, instead of rebuilding them from
+    /// each record's MD tag
+    #[allow(non_snake_case)]
+    pub fn with_reference(mut self, reference_FASTA_file: &std::path::Path) -> Result<Self, Error> {
+        self.reference_sequences = Some(fasta::read_sequences(reference_FASTA_file).map_err(Error::ReadingReference)?);
+        Ok(self)
+    }
+
+    fn convert_record(&self) -> Result<Record, Error> {
+        let record = &self.record;
+        let name = record.name().map_or_else(String::new, |name| name.to_string());
+
+        let reference_sequence_id = record.reference_sequence_id().ok_or_else(|| Error::MissingField("reference_sequence_id".to_string()))?;
+        let (reference_name, reference_map) = self.header.reference_sequences().get_index(reference_sequence_id).ok_or_else(|| {
+            Error::InvalidAlignment(format!("record \"{}\" is aligned to reference sequence {}, which is not in the header", name, reference_sequence_id))
+        })?;
+        let reference_start = usize::from(record.alignment_start().ok_or_else(|| Error::MissingField("alignment_start".to_string()))?) - 1;
+        let reference = SequenceRef {
+            name: reference_name.to_string(),
+            length: reference_map.length().get(),
+            start: reference_start,
+            end: reference_start + record.cigar().alignment_span()
+        };
+
+        let cigar: Vec<(usize, char)> = record.cigar().as_ref().iter().map(|op| (op.len(), operation_code(op.kind()))).collect();
+        let clipped_length = |operations: &mut dyn Iterator<Item = &(usize, char)>| -> usize {
+            operations.take_while(|(_, code)| *code == 'S' || *code == 'H').map(|(length, _)| length).sum()
+        };
+        let leading_clip = clipped_length(&mut cigar.iter());
+        let trailing_clip = clipped_length(&mut cigar.iter().rev());
+        let aligned_length: usize = cigar.iter().filter(|(_, code)| matches!(code, 'M' | 'I' | '=' | 'X')).map(|(length, _)| length).sum();
+        let flags = record.flags();
+        let strand_match = !flags.is_reverse_complemented();
+        // As in PAF, query coordinates are on the strand the read was sequenced from
+        let query_start = if strand_match { leading_clip } else { trailing_clip };
+        let query = SequenceRef {
+            name,
+            length: leading_clip + aligned_length + trailing_clip,
+            start: query_start,
+            end: query_start + aligned_length
+        };
+
+        let mut fields: HashMap<String, TagValue> = record.data().iter().map(|(tag, value)| {
+            (String::from_utf8_lossy(tag.as_ref()).into_owned(), tag_value(value))
+        }).collect();
+        // Only primary alignments are read, which SAM marks with flags rather than minimap2's tp:A
+        fields.entry("tp".to_string()).or_insert(TagValue::Character('P'));
+
+        let query_sequence = String::from_utf8_lossy(record.sequence().as_ref()).into_owned();
+        let operations = match self.reference_sequences {
+            Some(ref reference_sequences) => cigar::alignment_operations(&cigar, reference_sequence(Some(reference_sequences), &reference)?, Some(&query_sequence))?,
+            None => {
+                let md = fields.get("MD").and_then(TagValue::as_str).ok_or_else(|| Error::MissingReference(reference.name.clone()))?;
+                cigar::alignment_operations(&cigar, &cigar::reference_from_md(&cigar, md, &query_sequence)?, Some(&query_sequence))?
+            }
+        };
+        let alignment: Alignment = operations.into();
+
+        Ok(Record {
+            query,
+            reference,
+            strand_match,
+            num_matching_bases: alignment.operations().iter().filter(|operation| matches!(operation, AlignmentOperation::Identical(_))).map(|operation| operation.raw_length()).sum(),
+            num_mapped_bases: alignment.raw_length(),
+            mapping_quality: record.mapping_quality().map_or(255, u8::from),
+            fields,
+            alignment
+        })
+    }
+}
+
+impl Iterator for SAMRecordReader {
+    type Item = Result<Record, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let read = match self.source {
+                Source::Sam(ref mut reader) => reader.read_record_buf(&self.header, &mut self.record),
+                Source::Bam(ref mut reader, ref mut num_records) => {
+                    *num_records += 1;
+                    reader.read_record_buf(&self.header, &mut self.record)
+                },
+            };
+            match read {
+                Err(error) => { return Some(Err(Error::ReadingSAM(error))); },
+                Ok(0) => { return None; },
+                Ok(_) => {},
+            }
+            let flags = self.record.flags();
+            if flags.is_unmapped() || flags.is_secondary() || flags.is_supplementary() || self.record.sequence().is_empty() {
+                continue;
+            }
+            let name = self.record.name().map_or_else(String::new, |name| name.to_string());
+            return Some(self.convert_record().map_err(|error| match self.source {
+                // The record's line has been read, newline and all
+                Source::Sam(ref reader) => Error::InRecord(reader.get_ref().lines, name, Box::new(error)),
+                Source::Bam(_, num_records) => Error::InBAMRecord(num_records, name, Box::new(error)),
+            }));
+        }
+    }
+}
+
+fn operation_code(kind: Kind) -> char {
+    match kind {
+        Kind::Match => 'M',
+        Kind::Insertion => 'I',
+        Kind::Deletion => 'D',
+        Kind::Skip => 'N',
+        Kind::SoftClip => 'S',
+        Kind::HardClip => 'H',
+        Kind::Pad => 'P',
+        Kind::SequenceMatch => '=',
+        Kind::SequenceMismatch => 'X',
+    }
+}
+
+fn tag_value(value: &Value) -> TagValue {
+    match value {
+        Value::Character(character) => TagValue::Character(char::from(*character)),
+        Value::Int8(number) => TagValue::Integer(i64::from(*number)),
+        Value::UInt8(number) => TagValue::Integer(i64::from(*number)),
+        Value::Int16(number) => TagValue::Integer(i64::from(*number)),
+        Value::UInt16(number) => TagValue::Integer(i64::from(*number)),
+        Value::Int32(number) => TagValue::Integer(i64::from(*number)),
+        Value::UInt32(number) => TagValue::Integer(i64::from(*number)),
+        Value::Float(number) => TagValue::Float(f64::from(*number)),
+        Value::String(string) => TagValue::String(string.to_string()),
+        Value::Hex(string) => TagValue::Hex(string.to_string()),
+        Value::Array(Array::Int8(values)) => TagValue::IntegerArray(values.iter().copied().map(i64::from).collect()),
+        Value::Array(Array::UInt8(values)) => TagValue::IntegerArray(values.iter().copied().map(i64::from).collect()),
+        Value::Array(Array::Int16(values)) => TagValue::IntegerArray(values.iter().copied().map(i64::from).collect()),
+        Value::Array(Array::UInt16(values)) => TagValue::IntegerArray(values.iter().copied().map(i64::from).collect()),
+        Value::Array(Array::Int32(values)) => TagValue::IntegerArray(values.iter().copied().map(i64::from).collect()),
+        Value::Array(Array::UInt32(values)) => TagValue::IntegerArray(values.iter().copied().map(i64::from).collect()),
+        Value::Array(Array::Float(values)) => TagValue::FloatArray(values.iter().copied().map(f64::from).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "@HD\tVN:1.6\n@SQ\tSN:amp\tLN:15\n";
+
+    /// Read every record from a SAM file with the given records, and optionally the reference
+    /// sequence ATG AAA CCC GGG TTT
+    fn read_sam(name: &str, records: &[&str], with_reference: bool) -> Vec<Result<Record, Error>> {
+        let directory = std::env::temp_dir();
+        let prefix = format!("dms_tools_sam_{}_{}", std::process::id(), name);
+        let sam_path = directory.join(format!("{}.sam", prefix));
+        fs::write(&sam_path, records.iter().fold(HEADER.to_string(), |sam, record| sam + record + "\n")).expect("failed to write test SAM file");
+        let mut reader = SAMRecordReader::read(&sam_path).expect("failed to open test SAM file");
+        let reference_path = directory.join(format!("{}.fa", prefix));
+        if with_reference {
+            fs::write(&reference_path, ">amp\nATGAAACCCGGGTTT\n").expect("failed to write test reference");
+            reader = reader.with_reference(&reference_path).expect("failed to read test reference");
+        }
+        let records = reader.collect();
+        fs::remove_file(&sam_path).ok();
+        fs::remove_file(&reference_path).ok();
+        records
+    }
+
+    #[test]
+    fn only_primary_alignments_with_sequences_are_read() {
+        let records = [
+            // K2R, with one soft clipped base
+            "read\t0\tamp\t1\t60\t1S15M\t*\t0\t0\tcATGAGACCCGGGTTT\t*\tMD:Z:4A10",
+            // minimap2 omits the sequence of secondary alignments
+            "read\t256\tamp\t1\t0\t15M\t*\t0\t0\t*\t*\tMD:Z:15",
+            "read\t2048\tamp\t4\t60\t3M\t*\t0\t0\tAGA\t*\tMD:Z:1A1",
+            "unmapped\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\t*",
+            "primary_without_sequence\t0\tamp\t1\t60\t15M\t*\t0\t0\t*\t*\tMD:Z:15",
+        ];
+        for with_reference in [false, true] {
+            let records = read_sam(if with_reference { "primary_reference" } else { "primary" }, &records, with_reference);
+            assert_eq!(records.len(), 1);
+            let record = records[0].as_ref().expect("valid record");
+            assert_eq!((record.query.start, record.query.end, record.query.length), (1, 16, 16));
+            assert!(record.is_primary());
+            let alignment = record.alignment_subset(0, 15).expect("alignment covers the reference");
+            assert_eq!(alignment.call_coding_variants().expect("alignment has no indels"), vec![('K', 2, 'R')]);
+        }
+    }
+
+    #[test]
+    fn errors_give_the_line_and_record() {
+        let records = read_sam("no_md", &["unmapped\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\t*", "read\t0\tamp\t1\t60\t15M\t*\t0\t0\tATGAGACCCGGGTTT\t*"], false);
+        assert!(matches!(records.as_slice(), [Err(Error::InRecord(4, name, error))] if name == "read" && matches!(**error, Error::MissingReference(ref name) if name == "amp")));
+    }
+
+    #[test]
+    fn gzip_compressed_sam_is_read() {
+        let path = std::env::temp_dir().join(format!("dms_tools_sam_{}_compressed.sam.gz", std::process::id()));
+        let mut encoder = flate2::write::GzEncoder::new(fs::File::create(&path).expect("failed to create test SAM file"), flate2::Compression::default());
+        writeln!(encoder, "{}read\t0\tamp\t1\t60\t15M\t*\t0\t0\tATGAGACCCGGGTTT\t*\tMD:Z:4A10", HEADER).expect("failed to write test SAM file");
+        encoder.finish().expect("failed to write test SAM file");
+        let records: Vec<Result<Record, Error>> = crate::alignment::open(&path, None).expect("failed to open test SAM file").collect();
+        fs::remove_file(&path).ok();
+        assert!(matches!(records.as_slice(), [Ok(record)] if record.query.name == "read"));
+    }
+}
@@ -0,0 +1,132 @@
+use super::Error;
+
+/// The value of an optional SAM-style tag (e.g. `NM:i:3`), according to its type letter
+#[derive(Clone, Debug, PartialEq)]
+pub enum TagValue {
+    /// `A`
+    Character(char),
+    /// `i`
+    Integer(i64),
+    /// `f`
+    Float(f64),
+    /// `Z`
+    String(String),
+    /// `H`, a byte array written in hexadecimal
+    Hex(String),
+    /// `B` with an integer subtype (`c`, `C`, `s`, `S`, `i` or `I`)
+    IntegerArray(Vec<i64>),
+    /// `B` with subtype `f`
+    FloatArray(Vec<f64>),
+}
+
+impl TagValue {
+    /// Parse a tag written as `TAG:TYPE:VALUE`, returning its name and value
+    pub fn parse(raw_field: &str) -> Result<(String, Self), Error> {
+        let invalid = || Error::InvalidTag(raw_field.to_string());
+
+        let mut raw_tokens = raw_field.splitn(3, ':');
+        let (name, raw_type, raw_value) = match (raw_tokens.next(), raw_tokens.next(), raw_tokens.next()) {
+            (Some(name), Some(raw_type), Some(raw_value)) if name.len() == 2 => (name, raw_type, raw_value),
+            _ => { return Err(invalid()); }
+        };
+
+        let value = match raw_type {
+            "A" => {
+                let mut characters = raw_value.chars();
+                match (characters.next(), characters.next()) {
+                    (Some(character), None) => Self::Character(character),
+                    _ => { return Err(invalid()); }
+                }
+            },
+            "i" => Self::Integer(raw_value.parse().map_err(|_| invalid())?),
+            "f" => Self::Float(raw_value.parse().map_err(|_| invalid())?),
+            "Z" => Self::String(raw_value.to_string()),
+            "H" => Self::Hex(raw_value.to_string()),
+            "B" => {
+                let mut raw_elements = raw_value.split(',');
+                match raw_elements.next() {
+                    Some("c" | "C" | "s" | "S" | "i" | "I") => Self::IntegerArray(raw_elements.map(str::parse).collect::<Result<_, _>>().map_err(|_| invalid())?),
+                    Some("f") => Self::FloatArray(raw_elements.map(str::parse).collect::<Result<_, _>>().map_err(|_| invalid())?),
+                    _ => { return Err(invalid()); }
+                }
+            },
+            _ => { return Err(invalid()); }
+        };
+        Ok((name.to_string(), value))
+    }
+
+    pub fn as_char(&self) -> Option<char> {
+        match self {
+            Self::Character(character) => Some(*character),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Self::Integer(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            Self::Float(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    /// The value of a string (`Z`) or hexadecimal (`H`) tag
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(string) | Self::Hex(string) => Some(string),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw_field: &str) -> Option<TagValue> {
+        TagValue::parse(raw_field).ok().map(|(_, value)| value)
+    }
+
+    #[test]
+    fn tags_are_parsed_by_type() {
+        assert_eq!(TagValue::parse("tp:A:P").ok(), Some(("tp".to_string(), TagValue::Character('P'))));
+        assert_eq!(parse("NM:i:-3"), Some(TagValue::Integer(-3)));
+        assert_eq!(parse("de:f:0.5"), Some(TagValue::Float(0.5)));
+        // String values may contain colons
+        assert_eq!(parse("cs:Z::4*ag"), Some(TagValue::String(":4*ag".to_string())));
+        assert_eq!(parse("XH:H:1AE3"), Some(TagValue::Hex("1AE3".to_string())));
+    }
+
+    #[test]
+    fn arrays_are_parsed_by_subtype() {
+        assert_eq!(parse("XB:B:c,-1,2"), Some(TagValue::IntegerArray(vec![-1, 2])));
+        assert_eq!(parse("XB:B:I,4000000000"), Some(TagValue::IntegerArray(vec![4000000000])));
+        assert_eq!(parse("XB:B:f,0.5,2"), Some(TagValue::FloatArray(vec![0.5, 2.0])));
+        assert_eq!(parse("XB:B:i"), Some(TagValue::IntegerArray(vec![])));
+        assert_eq!(parse("XB:B:x,1"), None);
+        assert_eq!(parse("XB:B:i,1,a"), None);
+        assert_eq!(parse("XB:B:"), None);
+    }
+
+    #[test]
+    fn malformed_tags_are_rejected() {
+        for raw_field in ["NM", "NM:i", "NMX:i:1", "N:i:1", "NM:i:1.5", "de:f:x", "tp:A:", "tp:A:PS", "NM:q:1", "NM::1"] {
+            assert!(matches!(TagValue::parse(raw_field), Err(Error::InvalidTag(field)) if field == raw_field), "{} should be rejected", raw_field);
+        }
+    }
+
+    #[test]
+    fn values_are_only_given_for_their_type() {
+        assert_eq!(TagValue::Integer(3).as_int(), Some(3));
+        assert_eq!(TagValue::Integer(3).as_float(), None);
+        assert_eq!(TagValue::Float(0.5).as_int(), None);
+        assert_eq!(TagValue::String("x".to_string()).as_char(), None);
+        assert_eq!(TagValue::Hex("1A".to_string()).as_str(), Some("1A"));
+    }
+}
@@ -1 +1,2 @@
+pub mod fasta;
 pub mod fastq;
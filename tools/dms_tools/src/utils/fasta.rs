@@ -0,0 +1,82 @@
+use std::{collections::HashMap, fs, io::{self, prelude::*}, path};
+
+#[derive(Debug)]
+pub struct FASTARecord {
+    pub identifier: String,
+    pub sequence: String
+}
+
+impl FASTARecord {
+    /// The sequence name, i.e. the identifier up to the first whitespace, as used in alignments
+    pub fn name(&self) -> &str {
+        self.identifier.split_ascii_whitespace().next().unwrap_or("")
+    }
+}
+
+pub struct FASTAReader<R: Read> {
+    source: io::BufReader<R>,
+    buffer: String,
+    next_identifier: Option<String>
+}
+
+impl <R: Read> FASTAReader<R> {
+    pub fn read_fasta(source: R) -> FASTAReader<R> {
+        FASTAReader {
+            source: io::BufReader::new(source),
+            buffer: String::new(),
+            next_identifier: None
+        }
+    }
+}
+
+impl <R: Read> Iterator for FASTAReader<R> {
+    type Item = Result<FASTARecord, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let identifier = match self.next_identifier.take() {
+            Some(identifier) => identifier,
+            None => {
+                loop {
+                    self.buffer.clear();
+                    match self.source.read_line(&mut self.buffer) {
+                        Err(error) => { return Some(Err(error)); },
+                        Ok(0) => { return None; },
+                        Ok(_) if self.buffer.trim().is_empty() => {},
+                        Ok(_) if self.buffer.starts_with('>') => { break self.buffer[1..].trim_end().to_owned(); },
+                        Ok(_) => { return Some(Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "FASTA sequence does not start with a \">\" header line"))); },
+                    }
+                }
+            }
+        };
+
+        // Sequences may be split across several lines
+        let mut sequence = String::new();
+        loop {
+            self.buffer.clear();
+            match self.source.read_line(&mut self.buffer) {
+                Err(error) => { return Some(Err(error)); },
+                Ok(0) => { break; },
+                Ok(_) if self.buffer.starts_with('>') => {
+                    self.next_identifier = Some(self.buffer[1..].trim_end().to_owned());
+                    break;
+                },
+                Ok(_) => { sequence.push_str(self.buffer.trim()); },
+            }
+        }
+
+        Some(Ok(FASTARecord {
+            identifier,
+            sequence
+        }))
+    }
+}
+
+/// Read every sequence in a FASTA file (e.g. alignment references), keyed by name
+pub fn read_sequences(path: &path::Path) -> Result<HashMap<String, String>, std::io::Error> {
+    let mut sequences = HashMap::new();
+    for record in FASTAReader::read_fasta(fs::File::open(path)?) {
+        let record = record?;
+        sequences.insert(record.name().to_string(), record.sequence);
+    }
+    Ok(sequences)
+}
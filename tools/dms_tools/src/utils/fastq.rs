@@ -61,9 +61,9 @@ impl <R: Read> Iterator for FASTQReader<R> {
         self.buffer.clear();
 
         Some(Ok(FASTQRecord {
-            identifier: identifier,
-            sequence: sequence,
-            quality_scores: quality_scores
+            identifier,
+            sequence,
+            quality_scores
         }))
     }
 }